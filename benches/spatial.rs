@@ -0,0 +1,69 @@
+//! compares the spatial grid against scanning every enemy, run with `cargo bench --bench spatial`
+//! only the interest queries are measured, collisions between players and enemies are found by avian
+
+use std::time::{Duration, Instant};
+use dodgescrape2::*;
+
+const HALF_BOUNDARY: f32 = 3000.;
+const ENEMY_RADIUS: f32 = 20.;
+const INTEREST_RADIUS: f32 = 500.;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    println!("{:>8} {:>8} | {:>12} {:>12} | {:>12}", "enemies", "players", "rebuild", "grid interest", "naive interest");
+    for enemy_count in [5_000, 20_000, 50_000] {
+        for player_count in [10, 100] {
+            bench(enemy_count, player_count);
+        }
+    }
+}
+
+fn bench(enemy_count: usize, player_count: usize) {
+    let mut world = World::new();
+    let enemies: Vec<(Entity, Vec2)> = (0..enemy_count)
+        .map(|_| (world.spawn_empty().id(), random_position(HALF_BOUNDARY)))
+        .collect();
+    let players: Vec<Vec2> = (0..player_count).map(|_| random_position(HALF_BOUNDARY)).collect();
+
+    let mut grid = SpatialGrid::default();
+    let mut rebuild = Duration::ZERO;
+    let mut grid_interest = Duration::ZERO;
+    let mut naive_interest = Duration::ZERO;
+    // summed up and checked so the optimizer cannot drop the queries
+    let mut found = 0usize;
+
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        grid.clear();
+        for (entity, position) in &enemies {
+            grid.insert(*entity, *position, ENEMY_RADIUS);
+        }
+        rebuild += start.elapsed();
+
+        let start = Instant::now();
+        for player in &players {
+            found += grid.query(*player, INTEREST_RADIUS).count();
+        }
+        grid_interest += start.elapsed();
+
+        let start = Instant::now();
+        for player in &players {
+            let nearby = enemies.iter().filter(|(_, enemy)| enemy.distance(*player) <= INTEREST_RADIUS + ENEMY_RADIUS).count();
+            found -= nearby;
+        }
+        naive_interest += start.elapsed();
+    }
+
+    // both ways have to agree, otherwise the comparison is worthless
+    assert_eq!(found, 0, "grid and naive scan found different enemies");
+
+    let per_tick = |total: Duration| format!("{:.3} ms", total.as_secs_f64() * 1000. / ROUNDS as f64);
+    println!(
+        "{:>8} {:>8} | {:>12} {:>12} | {:>12}",
+        enemy_count,
+        player_count,
+        per_tick(rebuild),
+        per_tick(grid_interest),
+        per_tick(naive_interest),
+    );
+}
@@ -0,0 +1,111 @@
+use bevy::prelude::*;
+use bincode::{Decode, Encode};
+
+use crate::{boundary_walls, safe_zones, MyVec2};
+
+/// empty space between two areas, wider than any interest radius so nothing in one area is seen from another
+pub const AREA_GAP: f32 = 2000.;
+/// outer strip of each safe zone that moves players to the neighbouring area
+pub const PORTAL_WIDTH: f32 = 50.;
+
+/// one part of the map, areas lie next to each other from left to right and are only connected by portals
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct Area {
+	min: MyVec2,
+	max: MyVec2,
+	safe_zone_width: f32,
+}
+
+impl Area {
+	pub fn new(bounds: Rect, safe_zone_width: f32) -> Self {
+		Self {
+			min: bounds.min.into(),
+			max: bounds.max.into(),
+			safe_zone_width,
+		}
+	}
+
+	pub fn bounds(&self) -> Rect {
+		Rect::from_corners(self.min.into(), self.max.into())
+	}
+
+	/// the start and the end safe zone
+	pub fn safe_zones(&self) -> [Rect; 2] {
+		safe_zones(self.bounds(), self.safe_zone_width)
+	}
+
+	/// leading to the previous and to the next area, each at the outer edge of its safe zone
+	pub fn portals(&self) -> [Rect; 2] {
+		let bounds = self.bounds();
+		[
+			Rect::new(bounds.min.x, bounds.min.y, bounds.min.x + PORTAL_WIDTH, bounds.max.y),
+			Rect::new(bounds.max.x - PORTAL_WIDTH, bounds.min.y, bounds.max.x, bounds.max.y),
+		]
+	}
+
+	/// middle of the start safe zone, where players arrive from the previous area and respawn
+	pub fn start(&self) -> Vec2 {
+		self.safe_zones()[0].center()
+	}
+
+	/// middle of the end safe zone, where players arrive when they come back from the next area
+	pub fn end(&self) -> Vec2 {
+		self.safe_zones()[1].center()
+	}
+
+	pub fn walls(&self) -> [(Vec2, Vec2); 4] {
+		boundary_walls(self.bounds())
+	}
+
+	/// where enemies of the given radius can be placed without touching a wall or a safe zone
+	pub fn enemy_spawn_region(&self, enemy_radius: f32) -> Rect {
+		let bounds = self.bounds();
+		Rect::new(
+			bounds.min.x + self.safe_zone_width + enemy_radius,
+			bounds.min.y + enemy_radius,
+			bounds.max.x - self.safe_zone_width - enemy_radius,
+			bounds.max.y - enemy_radius,
+		)
+	}
+}
+
+/// every area of the map in order, the first one is where players join
+#[derive(Resource, Debug, Clone)]
+pub struct Areas(pub Vec<Area>);
+
+impl Areas {
+	/// puts areas of the given sizes next to each other, starting at x = 0 and centered on y = 0
+	pub fn layout(sizes: impl IntoIterator<Item = Vec2>, safe_zone_width: f32) -> Self {
+		let mut left = 0.;
+		let areas = sizes
+			.into_iter()
+			.map(|size| {
+				let bounds = Rect::new(left, -size.y / 2., left + size.x, size.y / 2.);
+				left += size.x + AREA_GAP;
+				Area::new(bounds, safe_zone_width)
+			})
+			.collect();
+		Self(areas)
+	}
+
+	/// the area a position belongs to, positions in a gap count to the closer area
+	pub fn index_at(&self, position: Vec2) -> usize {
+		self.0
+			.iter()
+			.position(|area| position.x <= area.bounds().max.x + AREA_GAP / 2.)
+			.unwrap_or(self.0.len().saturating_sub(1))
+	}
+
+	/// smallest rectangle containing every area
+	pub fn bounds(&self) -> Rect {
+		self.0
+			.iter()
+			.map(Area::bounds)
+			.reduce(|all, bounds| all.union(bounds))
+			.unwrap_or_default()
+	}
+}
+
+/// which area an enemy belongs to, enemies never leave their area
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InArea(pub usize);
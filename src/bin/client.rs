@@ -1,24 +1,64 @@
-use std::net::UdpSocket;
-use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+use std::collections::{HashMap, HashSet, VecDeque, hash_map::Entry};
 
 use dodgescrape2::*;
 
+/// seconds between two logins while the server did not answer
+const LOGIN_RETRY_INTERVAL: f64 = 1.;
+
 fn main() {
+    let config = match ClientConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{}", CLIENT_USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}\n{}", e, CLIENT_USAGE);
+            std::process::exit(2);
+        },
+    };
+    let server = match config.resolve() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    println!("connecting to {} as {:?}", server, config.name);
+
     App::new()
-        .insert_resource(ClientSocket::new())
-        .insert_resource(CursorPos(Vec2::ZERO))
+        .insert_resource(ClientSocket::new(server, config.log_malformed))
+        .insert_resource(InterpolationSettings {
+            delay: config.interpolation_delay,
+            max_extrapolation: config.max_extrapolation,
+        })
+        .insert_resource(config)
+        .insert_resource(PlayerNames::default())
         .insert_resource(EntityMap::default())
         .insert_resource(NetIDMap::default())
+        .insert_resource(Session::default())
+        .insert_resource(SnapshotBuffer::default())
+        .insert_resource(ServerQuantization::default())
+        .insert_resource(InputHistory::default())
+        .insert_resource(ServerClock::default())
         .add_plugins(DefaultPlugins)
-        .add_systems(Startup, setup)
-        .add_systems(Update, (receive_messages, cursor_position_system, player_movement_system))
+        .add_plugins((InputPlugin, GameSimulationPlugin { authoritative: false }))
+        .add_systems(Update, (login_system, receive_messages, send_input_system.after(SimulationSet), heartbeat_system, disconnect_system, resend_system, interpolation_system.after(receive_messages), name_label_system.after(receive_messages), player_appearance_system.after(receive_messages), downed_timer_system, respawn_system.after(receive_messages)))
         .run();
 }
 
 #[derive(Resource)]
 pub struct ClientSocket {
     pub socket: UdpSocket,
-    pub buf: [u8; 1000],
+    pub server: SocketAddr,
+    pub buf: [u8; MAX_PACKET_SIZE + 1], // one byte more so oversized datagrams are noticed instead of cut off
+    pub connection: Connection<ClientMessage, ServerMessage>,
+    pub server_tick: u32, // newest server tick received, stamped on what we send
+    pub malformed: u32, // packets from the server that could not be decoded
+    pub log_malformed: bool, // print every packet that could not be decoded
+    pub challenged: bool, // the server answered the login, it is not repeated anymore
 }
 
 #[derive(Resource, Default)]
@@ -26,204 +66,610 @@ struct NetIDMap(HashMap<Entity, NetIDType>);
 #[derive(Resource, Default)]
 struct EntityMap(HashMap<NetIDType, Entity>);
 
+/// display names of the players by their net id
+#[derive(Resource, Default)]
+struct PlayerNames(HashMap<NetIDType, String>);
+
+/// the player already got its name drawn above it
 #[derive(Component)]
-struct Controlled;
+struct NameLabel;
+
+/// the summary shown after dying, removed once the player is alive again
+#[derive(Component)]
+struct GameOverText;
+
+/// token handed out by the server after the login handshake, required for every further message
+#[derive(Resource, Default)]
+struct Session(Option<SessionToken>);
+
+/// inputs the server did not confirm yet, replayed on top of every authoritative position
+#[derive(Resource, Default)]
+struct InputHistory {
+    acked: Option<u32>,
+    pending: VecDeque<PlayerInput>,
+}
+
+/// remote entities are rendered this far in the past so there is usually a snapshot on both sides
+#[derive(Resource)]
+struct InterpolationSettings {
+    delay: f32,
+    max_extrapolation: f32, // how long an entity keeps moving on its own once the snapshots run out
+}
+
+/// estimate of the difference between the server clock and ours
+#[derive(Resource, Default)]
+struct ServerClock {
+    offset: Option<f64>,
+    tick_rate: u32, // told by the server on login
+}
+
+impl ServerClock {
+    /// returns the server time in seconds of the tick and updates the estimate with it
+    fn receive(&mut self, tick: u32, local_time: f64) -> f64 {
+        let server_time = tick as f64 / self.tick_rate.max(1) as f64;
+        let sample = server_time - local_time;
+        // smoothed so a single late packet does not shift everything
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) * 0.05,
+            None => sample,
+        });
+        server_time
+    }
+    fn server_time(&self, local_time: f64) -> Option<f64> {
+        Some(local_time + self.offset?)
+    }
+}
+
+/// positions of a remote entity with the server time they belong to
+#[derive(Component, Default)]
+struct InterpolationBuffer(VecDeque<(f64, Vec2)>);
+
+impl InterpolationBuffer {
+    fn push(&mut self, server_time: f64, position: Vec2) {
+        // snapshots can arrive out of order, older ones are of no use anymore
+        if self.0.back().is_some_and(|(last, _)| server_time <= *last) {
+            return;
+        }
+        self.0.push_back((server_time, position));
+    }
+    fn sample(&mut self, render_time: f64, max_extrapolation: f32) -> Option<Vec2> {
+        // keep a single snapshot before the render time to interpolate from
+        while self.0.len() > 2 && self.0[1].0 <= render_time {
+            self.0.pop_front();
+        }
+        let (from_time, from) = *self.0.front()?;
+        let Some(&(to_time, to)) = self.0.get(1) else {
+            return Some(from);
+        };
+        // past the newest snapshot we keep going in the last direction for a bit
+        let render_time = render_time.min(to_time + max_extrapolation as f64);
+        let t = ((render_time - from_time) / (to_time - from_time)) as f32;
+        Some(from.lerp(to, t.max(0.)))
+    }
+}
+
+/// how the server encodes positions, known once the login went through
+#[derive(Resource, Default)]
+struct ServerQuantization(Option<Quantization>);
+
+/// partially received enemy snapshot
+struct PartialSnapshot {
+    fragments: HashSet<u8>,
+    fragment_count: u8,
+    enemies: HashMap<NetIDType, EnemyPackage>,
+}
+
+/// complete enemy snapshots that the server may use as delta baselines
+#[derive(Resource, Default)]
+struct SnapshotBuffer {
+    complete: VecDeque<(u32, HashMap<NetIDType, EnemyPackage>)>,
+    partial: HashMap<u32, PartialSnapshot>,
+}
+
+impl SnapshotBuffer {
+    fn get(&self, snapshot: u32) -> Option<&HashMap<NetIDType, EnemyPackage>> {
+        self.complete.iter().find(|(id, _)| *id == snapshot).map(|(_, enemies)| enemies)
+    }
+    /// returns the snapshot id once its last fragment arrived
+    fn receive_fragment(&mut self, enemy_snapshot: &EnemySnapshot, enemies: Vec<EnemyPackage>) -> Option<u32> {
+        let partial = self.partial.entry(enemy_snapshot.snapshot).or_insert_with(|| PartialSnapshot {
+            fragments: HashSet::new(),
+            fragment_count: enemy_snapshot.fragment_count,
+            enemies: HashMap::new(),
+        });
+        partial.fragments.insert(enemy_snapshot.fragment);
+        partial.enemies.extend(enemies.into_iter().map(|enemy| (enemy.net_id, enemy)));
+
+        if partial.fragments.len() < partial.fragment_count as usize {
+            // fragments of snapshots that never complete are dropped eventually
+            let oldest = self.partial.keys().min().copied();
+            if let Some(oldest) = oldest.filter(|_| self.partial.len() > SNAPSHOT_HISTORY) {
+                self.partial.remove(&oldest);
+            }
+            return None;
+        }
+
+        let partial = self.partial.remove(&enemy_snapshot.snapshot)?;
+        self.complete.push_back((enemy_snapshot.snapshot, partial.enemies));
+        if self.complete.len() > SNAPSHOT_HISTORY {
+            self.complete.pop_front();
+        }
+        Some(enemy_snapshot.snapshot)
+    }
+}
 
 impl ClientSocket {
-    pub fn new() -> Self {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    pub fn new(server: SocketAddr, log_malformed: bool) -> Self {
+        let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).unwrap();
         socket.set_nonblocking(true).unwrap();
         Self {
             socket,
-            buf: [0; 1000],
+            server,
+            buf: [0; MAX_PACKET_SIZE + 1],
+            connection: Connection::new(Instant::now()),
+            server_tick: 0,
+            malformed: 0,
+            log_malformed,
+            challenged: false,
+        }
+    }
+    pub fn send(&mut self, message: ClientMessage) {
+        match self.connection.send(message.clone(), self.server_tick, Instant::now()) {
+            Ok(bytes) => {
+                if let Err(e) = self.socket.send_to(&bytes, self.server) {
+                    eprintln!("could not send to {}: {}", self.server, e);
+                }
+            },
+            Err(e) => eprintln!("could not send {:?}: {}", message, e),
         }
     }
-    pub fn send(&self, bytes: &[u8]) {
-        self.socket.send_to(bytes, "127.0.0.1:7878").unwrap();
+    pub fn resend(&mut self) {
+        for bytes in self.connection.resend(Instant::now()) {
+            let _ = self.socket.send_to(&bytes, self.server);
+        }
     }
 }
 
-fn setup(
-    socket: Res<ClientSocket>,
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+/// the challenge is not resent by the server, so the login is repeated until one arrives
+fn login_system(
+    time: Res<Time>,
+    config: Res<ClientConfig>,
+    session: Res<Session>,
+    mut socket: ResMut<ClientSocket>,
+    mut last_sent: Local<Option<f64>>,
 ) {
-    let login_message = ClientMessage::Login;
-    socket.send(&login_message.encode());
+    if socket.challenged || session.0.is_some() {
+        return;
+    }
+    let now = time.elapsed_secs_f64();
+    if last_sent.is_some_and(|last_sent| now - last_sent < LOGIN_RETRY_INTERVAL) {
+        return;
+    }
+    *last_sent = Some(now);
+    socket.send(ClientMessage::Login(config.name.clone()));
+}
 
+/// draws the walls, safe zones and portals of every area the server described, they are only scenery on the client
+fn spawn_map(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    server_info: &ServerInfo,
+) {
     let mut rng = rand::rng();
-    // + Spawn static boundary colliders
-    let half_boundary = 3000.0;
-    let thickness = 10.0;
     let wall_material = MeshMaterial2d(materials.add(Color::srgb(
         rng.random_range(0.0..4.0),
         rng.random_range(0.0..4.0),
         rng.random_range(0.0..4.0),
     )));
-    for &pos in &[-half_boundary, half_boundary] {
-        // vertical walls
-        commands.spawn((
-            Mesh2d(meshes.add(Rectangle::new(thickness, half_boundary * 2.))),
-            wall_material.clone(),
-            Transform::from_xyz(pos, 0., 0.),
-        ));
-        // horizontal walls
-        commands.spawn((
-            Mesh2d(meshes.add(Rectangle::new(half_boundary * 2., thickness))),
-            wall_material.clone(),
-            Transform::from_xyz(0., pos, 0.),
-        ));
+    let safe_zone_material = MeshMaterial2d(materials.add(Color::srgb(0.15, 0.15, 0.15)));
+    let portal_material = MeshMaterial2d(materials.add(Color::srgb(0.2, 0.2, 0.6)));
+    for area in &server_info.areas {
+        for (position, size) in area.walls() {
+            commands.spawn((
+                Mesh2d(meshes.add(Rectangle::new(size.x, size.y))),
+                wall_material.clone(),
+                Transform::from_translation(position.extend(0.)),
+            ));
+        }
+        // portals are drawn on top of the safe zones they are part of
+        let zones = area.safe_zones().map(|zone| (zone, safe_zone_material.clone(), -2.));
+        let portals = area.portals().map(|portal| (portal, portal_material.clone(), -1.));
+        for (rect, material, z) in zones.into_iter().chain(portals) {
+            commands.spawn((
+                Mesh2d(meshes.add(Rectangle::new(rect.width(), rect.height()))),
+                material,
+                Transform::from_translation(rect.center().extend(z)),
+            ));
+        }
     }
 }
 
-fn cursor_position_system(
-    window: Single<&Window, With<PrimaryWindow>>,
-    mut cursor: ResMut<CursorPos>,
+/// remembers the predicted inputs until the server confirms them
+fn send_input_system(
+    mut local_inputs: MessageReader<LocalInput>,
+    mut client_socket: ResMut<ClientSocket>,
+    mut input_history: ResMut<InputHistory>,
+    session: Res<Session>,
 ) {
-    let window_center = Vec2::new(window.width() / 2.0, window.height() / 2.0);
+    let Some(token) = session.0 else {
+        return;
+    };
+    for LocalInput(input) in local_inputs.read() {
+        input_history.pending.push_back(*input);
+        client_socket.send(ClientMessage::SetVelocity(token, *input));
+    }
+}
 
-    if let Some(cursor_position) = window.cursor_position() {
-        cursor.0 = (cursor_position - window_center) * Vec2::new(1., -1.); // relative to center
+/// labels players with their name as soon as both the entity and the name are known
+fn name_label_system(
+    mut commands: Commands,
+    player_names: Res<PlayerNames>,
+    net_id_map: Res<NetIDMap>,
+    players: Query<Entity, (With<Player>, Without<NameLabel>)>,
+) {
+    for entity in &players {
+        let Some(name) = net_id_map.0.get(&entity).and_then(|net_id| player_names.0.get(net_id)) else {
+            continue;
+        };
+        commands.entity(entity).insert(NameLabel).with_child((
+            Text2d::new(name.clone()),
+            TextFont {
+                font_size: 14.,
+                ..default()
+            },
+            Transform::from_xyz(0., PLAYER_RADIUS + 12., 1.),
+        ));
     }
 }
 
-fn player_movement_system(
-    cursor: Res<CursorPos>,
-    player_query: Query<(Entity, &mut Velocity, &Alive), (With<Player>, With<Controlled>)>,
+/// asks the server to respawn while the game over summary is shown, it decides whether that is allowed yet
+fn respawn_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut commands: Commands,
     mut client_socket: ResMut<ClientSocket>,
-    mut net_id_map: Res<NetIDMap>,
+    session: Res<Session>,
+    game_over_texts: Query<Entity, With<GameOverText>>,
+    controlled: Query<&PlayerState, With<Controlled>>,
 ) {
-    for (player_entity, mut velocity, alive) in player_query {
-        if alive.0 || true {
-            let speed = 300.0; // units per second
-            let length = cursor.0.length();
-            let threshold = 200.;
-            if length == 0. {
-                continue;
-            }
-            let percentage = length / threshold;
+    if controlled.iter().any(|state| *state == PlayerState::Alive) {
+        for entity in &game_over_texts {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+    let Some(token) = session.0 else {
+        return;
+    };
+    if !game_over_texts.is_empty() && keys.just_pressed(KeyCode::KeyR) {
+        client_socket.send(ClientMessage::Respawn(token));
+    }
+}
 
-            velocity.0 = cursor.0.normalize() * percentage * speed;
+/// colors players by their state, spectators are hidden together with their name
+fn player_appearance_system(
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    players: Query<(&PlayerState, &MeshMaterial2d<ColorMaterial>, &mut Visibility), (With<Player>, Changed<PlayerState>)>,
+) {
+    for (state, material, mut visibility) in players {
+        let color = match state {
+            PlayerState::Alive => Color::srgb(0., 1., 0.),
+            PlayerState::Downed(_) => Color::srgb(1., 1., 0.),
+            PlayerState::Dead(_) => Color::srgb(0.3, 0.3, 0.3),
+            PlayerState::Spectating => Color::NONE,
+        };
+        if let Some(material) = materials.get_mut(&material.0) {
+            material.color = color;
         }
-        else {
-            velocity.0 = Vec2::ZERO;
+        *visibility = if *state == PlayerState::Spectating { Visibility::Hidden } else { Visibility::Inherited };
+    }
+}
+
+/// a ring around downed players that shrinks while their time to be revived runs out
+fn downed_timer_system(
+    mut gizmos: Gizmos,
+    players: Query<(&Transform, &PlayerState), With<Player>>,
+) {
+    for (transform, state) in players {
+        let PlayerState::Downed(remaining) = state else {
+            continue;
+        };
+        let arc = std::f32::consts::TAU * (remaining / DOWNED_DURATION).clamp(0., 1.);
+        let isometry = Isometry2d::from_translation(transform.translation.truncate());
+        gizmos.arc_2d(isometry, arc, PLAYER_RADIUS + 6., Color::srgb(1., 1., 0.));
+    }
+}
+
+fn heartbeat_system(
+    time: Res<Time>,
+    mut client_socket: ResMut<ClientSocket>,
+    session: Res<Session>,
+    mut since_heartbeat: Local<f32>,
+) {
+    let Some(token) = session.0 else {
+        return;
+    };
+    *since_heartbeat += time.delta_secs();
+    if *since_heartbeat >= HEARTBEAT_INTERVAL {
+        *since_heartbeat = 0.;
+        client_socket.send(ClientMessage::Heartbeat(token));
+    }
+}
+
+fn interpolation_system(
+    time: Res<Time>,
+    settings: Res<InterpolationSettings>,
+    server_clock: Res<ServerClock>,
+    query: Query<(&mut Transform, &mut InterpolationBuffer)>,
+) {
+    let Some(server_time) = server_clock.server_time(time.elapsed_secs_f64()) else {
+        return;
+    };
+    let render_time = server_time - settings.delay as f64;
+    for (mut transform, mut interpolation_buffer) in query {
+        if let Some(position) = interpolation_buffer.sample(render_time, settings.max_extrapolation) {
+            transform.translation = position.extend(transform.translation.z);
         }
+    }
+}
+
+/// reliable messages are sent again until the server acks them
+fn resend_system(
+    mut client_socket: ResMut<ClientSocket>,
+) {
+    client_socket.resend();
+}
 
-        let net_id = net_id_map.0.get(&player_entity).unwrap();
-        client_socket.send(&ClientMessage::SetVelocity(*net_id, velocity.0.into()).encode());
+/// tells the server we are gone so our player does not wait for the timeout
+fn disconnect_system(
+    mut close_requests: MessageReader<WindowCloseRequested>,
+    mut client_socket: ResMut<ClientSocket>,
+    session: Res<Session>,
+) {
+    if close_requests.read().next().is_none() {
+        return;
+    }
+    if let Some(token) = session.0 {
+        client_socket.send(ClientMessage::Disconnect(token));
     }
 }
 
 fn receive_messages(
+    time: Res<Time>,
+    mut server_clock: ResMut<ServerClock>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    (mut meshes, mut materials): (ResMut<Assets<Mesh>>, ResMut<Assets<ColorMaterial>>),
     mut client_socket: ResMut<ClientSocket>,
+    mut player_names: ResMut<PlayerNames>,
     mut entity_map: ResMut<EntityMap>,
     mut net_id_map: ResMut<NetIDMap>,
-    mut enemy_query: Query<&mut Transform, (With<Enemy>, Without<Player>)>, // without are required to exclude the queries
-    mut player_query: Query<&mut Transform, (With<Player>, Without<Enemy>)>, // without are required to exclude the queries
+    mut session: ResMut<Session>,
+    mut snapshot_buffer: ResMut<SnapshotBuffer>,
+    mut server_quantization: ResMut<ServerQuantization>,
+    mut enemy_query: Query<&mut InterpolationBuffer, (With<Enemy>, Without<Player>)>, // without are required to exclude the queries
+    (mut player_query, mut player_states): (
+        Query<&mut InterpolationBuffer, (With<Player>, Without<Enemy>, Without<Controlled>)>, // without are required to exclude the queries
+        Query<&mut PlayerState, With<Player>>,
+    ),
+    mut controlled_query: Query<&mut Transform, (With<Controlled>, Without<Enemy>)>,
+    mut input_history: ResMut<InputHistory>,
+    mut app_exit: MessageWriter<AppExit>,
 ) {
-    let ClientSocket { socket, buf } = &mut *client_socket;
-
-    while let Ok((len, addr)) = socket.recv_from(buf) {
-        let server_message_option = ServerMessage::decode(&buf[..len]);
-        match server_message_option {
-            Some(server_message) => match server_message {
-                ServerMessage::Ok(net_id) => {
-                    println!("player was created successfully with id {:?}", net_id);
-
-                    if !entity_map.0.contains_key(&net_id) {
-                        let id = commands.spawn((
-                            Controlled,
-                            Camera2d,
-                            Camera {
-                                clear_color: ClearColorConfig::Custom(Color::BLACK),
-                                ..default()
-                            },
-                            Tonemapping::TonyMcMapface,
-                            Bloom::default(),
-                            DebandDither::Enabled,
-
-                            Mesh2d(meshes.add(Circle::new(20.))),
-                            Transform::default(),
-                            Velocity(Vec2::new(0., 0.)),
-                            MeshMaterial2d(materials.add(Color::srgb(0., 1., 0.))),
-                            Player,
-                            Alive(true),
-                            Radius(20.),
-                        )).id();
-
-                        entity_map.0.insert(net_id, id);
-                        net_id_map.0.insert(id, net_id);
-                    }
-                },
-                ServerMessage::UpdateEnemies(enemy_packages) => {
-                    let mut rng = rand::rng();
-
-                    for enemy_package in enemy_packages {
-                        // check if enemy exists on local data
-                        if let Some(enemy_entity) = entity_map.0.get(&enemy_package.net_id) {
-                            if let Ok(mut enemy_transform) = enemy_query.get_mut(*enemy_entity) {
-                                 enemy_transform.translation = enemy_package.position.clone().into();
-                            }
-                        }
+    let client_socket = &mut *client_socket;
 
-                        // create enemy if doesn't exist on local data
-                        if !entity_map.0.contains_key(&enemy_package.net_id) {
-                            let material = MeshMaterial2d(materials.add(Color::srgb(
-                                rng.random_range(0.0..4.0),
-                                rng.random_range(0.0..4.0),
-                                rng.random_range(0.0..4.0),
-                            )));
+    while let Ok((len, addr)) = client_socket.socket.recv_from(&mut client_socket.buf) {
+        if addr != client_socket.server {
+            continue;
+        }
+        let server_messages_result = client_socket.connection.receive(&client_socket.buf[..len], Instant::now());
+        match server_messages_result {
+            Ok(server_messages) => for (tick, server_message) in server_messages {
+                client_socket.server_tick = client_socket.server_tick.max(tick);
+                match server_message {
+                    ServerMessage::Challenge(challenge) => {
+                        // a late copy of a challenge must not log in a second time
+                        if session.0.is_none() {
+                            client_socket.challenged = true;
+                            client_socket.send(ClientMessage::ChallengeResponse(challenge));
+                        }
+                    },
+                    ServerMessage::Ok(net_id, token, server_info) => {
+                        println!("player was created successfully with id {:?}", net_id);
+                        session.0 = Some(token);
+                        server_quantization.0 = Some(server_info.quantization);
+                        server_clock.tick_rate = server_info.tick_rate;
 
+                        if let Entry::Vacant(entry) = entity_map.0.entry(net_id) {
+                            spawn_map(&mut commands, &mut meshes, &mut materials, &server_info);
+                            let spawn_point = server_info.areas.first().map(Area::start).unwrap_or_default();
                             let id = commands.spawn((
-                                Mesh2d(meshes.add(Circle::new(enemy_package.radius))),
-                                material,
-                                Transform::from_translation(enemy_package.position.into()),
-                                Velocity(Vec2::new(0., 0.)),
-                                Enemy,
-                                Radius(enemy_package.radius),
+                                Controlled,
+                                Camera2d,
+                                Camera {
+                                    clear_color: ClearColorConfig::Custom(Color::BLACK),
+                                    ..default()
+                                },
+                                Tonemapping::TonyMcMapface,
+                                Bloom::default(),
+                                DebandDither::Enabled,
+
+                                player_bundle(spawn_point),
+                                Mesh2d(meshes.add(Circle::new(PLAYER_RADIUS))),
+                                MeshMaterial2d(materials.add(Color::srgb(0., 1., 0.))),
                             )).id();
 
-                            entity_map.0.insert(enemy_package.net_id, id);
-                            net_id_map.0.insert(id, enemy_package.net_id);
+                            entry.insert(id);
+                            net_id_map.0.insert(id, net_id);
                         }
-                    }
-                },
-                ServerMessage::UpdatePlayers(players) => {
-                    for player in players {
-                        // check if player exists on local data
-                        if let Some(player_entity) = entity_map.0.get(&player.net_id) {
-                            let player_transform_result = player_query.get_mut(*player_entity);
-                            match player_transform_result {
-                                Ok(mut player_transform) => {
-                                    player_transform.translation = player.position.clone().into();
-                                },
-                                Err(_) => { },
+                    },
+                    ServerMessage::EnemiesEntered(enemy_packages) => {
+                        // positions can only be read once the login went through
+                        let Some(quantization) = server_quantization.0 else {
+                            continue;
+                        };
+                        let mut rng = rand::rng();
+
+                        for enemy_package in enemy_packages {
+                            // known enemies are moved by the snapshots
+                            // create enemy if doesn't exist on local data
+                            if let Entry::Vacant(entry) = entity_map.0.entry(enemy_package.net_id) {
+                                let material = MeshMaterial2d(materials.add(Color::srgb(
+                                    rng.random_range(0.0..4.0),
+                                    rng.random_range(0.0..4.0),
+                                    rng.random_range(0.0..4.0),
+                                )));
+
+                                let id = commands.spawn((
+                                    Mesh2d(meshes.add(Circle::new(enemy_package.radius))),
+                                    material,
+                                    Transform::from_translation(quantization.dequantize(enemy_package.position).extend(0.)),
+                                    Velocity(Vec2::new(0., 0.)),
+                                    InterpolationBuffer::default(),
+                                    Enemy,
+                                    Radius(enemy_package.radius),
+                                )).id();
+
+                                entry.insert(id);
+                                net_id_map.0.insert(id, enemy_package.net_id);
+                            }
+                        }
+                    },
+                    ServerMessage::EntitiesLeft(net_ids) => {
+                        for net_id in net_ids {
+                            if let Some(entity) = entity_map.0.remove(&net_id) {
+                                net_id_map.0.remove(&entity);
+                                commands.entity(entity).despawn();
                             }
                         }
+                    },
+                    ServerMessage::UpdateEnemies(enemy_snapshot) => {
+                        // positions can only be read once the login went through
+                        let Some(quantization) = server_quantization.0 else {
+                            continue;
+                        };
+                        let server_time = server_clock.receive(tick, time.elapsed_secs_f64());
+                        let baseline = enemy_snapshot.baseline.and_then(|baseline| snapshot_buffer.get(baseline));
+                        let mut enemy_packages = Vec::with_capacity(enemy_snapshot.enemies.len());
+                        for enemy_delta in &enemy_snapshot.enemies {
+                            let Some(enemy_package) = enemy_delta.apply(baseline.and_then(|enemies| enemies.get(&enemy_delta.net_id))) else {
+                                continue;
+                            };
 
-                        // create player if doesn't exist on local data
-                        if !entity_map.0.contains_key(&player.net_id) {
-                            let id = commands.spawn((
-                                Mesh2d(meshes.add(Circle::new(20.))),
-                                Transform::from_translation(player.position.into()),
-                                Velocity(Vec2::new(0., 0.)),
-                                MeshMaterial2d(materials.add(Color::srgb(0., 1., 0.))),
-                                Player,
-                                Alive(true),
-                                Radius(20.),
-                            )).id();
+                            // only known enemies are moved, a late update must not bring back an enemy that already left
+                            let enemy_entity = entity_map.0.get(&enemy_package.net_id);
+                            if let Some(Ok(mut interpolation_buffer)) = enemy_entity.map(|entity| enemy_query.get_mut(*entity)) {
+                                interpolation_buffer.push(server_time, quantization.dequantize(enemy_package.position));
+                            }
+                            enemy_packages.push(enemy_package);
+                        }
 
-                            entity_map.0.insert(player.net_id, id);
-                            net_id_map.0.insert(id, player.net_id);
+                        let completed = snapshot_buffer.receive_fragment(&enemy_snapshot, enemy_packages);
+                        if let (Some(snapshot), Some(token)) = (completed, session.0) {
+                            client_socket.send(ClientMessage::AckSnapshot(token, snapshot));
                         }
-                    }
-                },
+                    },
+                    ServerMessage::UpdatePlayers(players) => {
+                        // positions can only be read once the login went through
+                        let Some(quantization) = server_quantization.0 else {
+                            continue;
+                        };
+                        let server_time = server_clock.receive(tick, time.elapsed_secs_f64());
+                        for player in players {
+                            // check if player exists on local data
+                            if let Some(player_entity) = entity_map.0.get(&player.net_id) {
+                                if let Ok(mut interpolation_buffer) = player_query.get_mut(*player_entity) {
+                                    interpolation_buffer.push(server_time, quantization.dequantize(player.position));
+                                }
+                                // the controlled player is included as well, its input stops while it is not alive
+                                if let Ok(mut state) = player_states.get_mut(*player_entity) {
+                                    state.set_if_neq(player.state);
+                                }
+                            }
+
+                            // create player if doesn't exist on local data
+                            if let Entry::Vacant(entry) = entity_map.0.entry(player.net_id) {
+                                let id = commands.spawn((
+                                    player_bundle(quantization.dequantize(player.position)),
+                                    InterpolationBuffer::default(),
+                                    Mesh2d(meshes.add(Circle::new(PLAYER_RADIUS))),
+                                    MeshMaterial2d(materials.add(Color::srgb(0., 1., 0.))),
+                                )).insert(player.state).id();
+
+                                entry.insert(id);
+                                net_id_map.0.insert(id, player.net_id);
+                            }
+                        }
+                    },
+                    ServerMessage::InputAck(last_input, position) => {
+                        let Some(quantization) = server_quantization.0 else {
+                            continue;
+                        };
+                        // acks are unreliable, an older one would roll the prediction back
+                        if input_history.acked.is_some_and(|acked| last_input < acked) {
+                            continue;
+                        }
+                        input_history.acked = Some(last_input);
+                        input_history.pending.retain(|input| input.sequence > last_input);
+
+                        // start from where the server says we are and replay what it has not seen yet
+                        for mut transform in &mut controlled_query {
+                            let z = transform.translation.z;
+                            transform.translation = quantization.dequantize(position).extend(z);
+                            for input in &input_history.pending {
+                                input.apply(&mut transform.translation);
+                            }
+                        }
+                    },
+                    ServerMessage::PlayersJoined(players) => {
+                        for player in players {
+                            println!("{} joined", player.name);
+                            player_names.0.insert(player.net_id, player.name);
+                        }
+                    },
+                    ServerMessage::PlayerLeft(net_id) => {
+                        if let Some(name) = player_names.0.remove(&net_id) {
+                            println!("{} left", name);
+                        }
+                        if let Some(player_entity) = entity_map.0.remove(&net_id) {
+                            net_id_map.0.remove(&player_entity);
+                            commands.entity(player_entity).despawn();
+                        }
+                    },
+                    ServerMessage::Rejected(reason) => {
+                        eprintln!("the server refused the login: {}", reason);
+                        app_exit.write(AppExit::error());
+                    },
+                    ServerMessage::GameOver(stats) => {
+                        println!("game over: {:?}", stats);
+                        commands.spawn((
+                            GameOverText,
+                            Text::new(format!(
+                                "game over\nsurvived {:.1} seconds, downed {} times, revived {} teammates\npress R to respawn",
+                                stats.survival_time, stats.downs, stats.revives,
+                            )),
+                            TextLayout::new_with_justify(Justify::Center),
+                            Node {
+                                position_type: PositionType::Absolute,
+                                top: Val::Percent(40.),
+                                width: Val::Percent(100.),
+                                ..default()
+                            },
+                        ));
+                    },
+                }
+            },
+            Err(DecodeError::VersionMismatch(version)) => {
+                eprintln!("the server refused the login: the server uses protocol version {} but this client uses {}", version, PROTOCOL_VERSION);
+                app_exit.write(AppExit::error());
+            },
+            Err(e) => {
+                // a broken datagram is dropped like a lost one, the reliable channel resends what matters
+                client_socket.malformed += 1;
+                if client_socket.log_malformed {
+                    eprintln!("ignored malformed packet number {} from the server: {}", client_socket.malformed, e);
+                }
             },
-            None => todo!(),
         }
     }
 }
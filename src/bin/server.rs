@@ -1,22 +1,67 @@
-use std::net::{SocketAddr, UdpSocket};
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 use dodgescrape2::*;
-use avian2d::prelude::*;
+use avian2d::prelude::{ColliderDisabled, RigidBodyDisabled};
+#[cfg(not(feature = "debug-view"))]
+use bevy::app::ScheduleRunnerPlugin;
 
-const ENEMY_RADIUS: f32 = 20.;
+/// how long a peer that sent too many malformed packets is ignored
+const BLOCK_DURATION: Duration = Duration::from_secs(300);
+/// most hosts whose malformed packets are counted at once, packets from further hosts are dropped without counting
+const MAX_MALFORMED_PEERS: usize = 4096;
+/// how often the network thread forgets idle connections and old entries, also the longest it sleeps when nothing has to be resent
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+/// pause of the reader thread after the second failed receive in a row, doubled with every further one
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+/// shortest time between two answers to a peer speaking another protocol version
+const VERSION_REPLY_INTERVAL: Duration = Duration::from_secs(1);
+/// how long the answer to a challenge is accepted, the client answers reliably right away
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+/// most logins waiting for their challenge to be answered, further ones are ignored until some expire
+const MAX_PENDING_CHALLENGES: usize = 256;
+/// how far the inputs of a player may run ahead of or lag behind the server clock, covers network jitter
+const INPUT_CLOCK_TOLERANCE: Duration = Duration::from_millis(500);
+
+/// what the network thread does about packets that cannot be decoded
+pub struct MalformedPolicy {
+    pub log: bool,
+    pub block_threshold: u32, // peers are blocked after this many malformed packets, 0 never blocks
+}
 
 pub struct ServerSocket {
     pub socket: UdpSocket,
-    pub buf: [u8; 1000],
+    pub connections: HashMap<SocketAddr, Connection<ServerMessage, ClientMessage>>,
+    // counted per host and not per port, otherwise a blocked peer just picks another port
+    pub malformed: HashMap<IpAddr, (u32, Instant)>, // malformed packets per host and when the last one came in
+    pub blocked: HashMap<IpAddr, Instant>, // hosts we ignore until the given time
+    pub version_replies: HashMap<SocketAddr, Instant>, // when a peer was last told our protocol version
+    pub policy: MalformedPolicy,
+    pub idle: Duration, // connections that did not receive anything for this long are forgotten
+    pub max_connections: usize, // logins beyond this are ignored until idle connections are forgotten
+    pub wakeup: Instant, // earliest time a message may have to be resent or the maps have to be cleaned up
+    pub next_housekeeping: Instant,
 }
 
 impl ServerSocket {
     pub fn new(
         socket: UdpSocket,
+        policy: MalformedPolicy,
+        idle: Duration,
+        max_connections: usize,
     ) -> Self {
         Self {
             socket,
-            buf: [0; 1000],
+            connections: HashMap::new(),
+            malformed: HashMap::new(),
+            blocked: HashMap::new(),
+            version_replies: HashMap::new(),
+            policy,
+            idle,
+            max_connections,
+            wakeup: Instant::now(),
+            next_housekeeping: Instant::now(),
         }
     }
     pub fn send_to(&self, bytes: &[u8], addr: SocketAddr) -> bool {
@@ -25,59 +70,251 @@ impl ServerSocket {
             Err(_) => false,
         }
     }
+    pub fn send_message(&mut self, message: ServerMessage, tick: u32, addr: SocketAddr, now: Instant) {
+        // never resent, so a login with a forged address cannot make us flood someone else
+        if message.unconnected() {
+            match Packet::unconnected(tick, message).encode() {
+                Ok(bytes) => {
+                    self.send_to(&bytes, addr);
+                },
+                Err(e) => eprintln!("could not send message to {}: {}", addr, e),
+            }
+            return;
+        }
+        // the game only answers peers that logged in, their connection is gone once they are idle for too long
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return;
+        };
+        let sent = connection.send(message, tick, now);
+        if let Some(resend) = connection.next_resend() {
+            self.wakeup = self.wakeup.min(resend);
+        }
+        match sent {
+            Ok(bytes) => {
+                self.send_to(&bytes, addr);
+            },
+            Err(e) => eprintln!("could not send message to {}: {}", addr, e),
+        }
+    }
+    /// sends reliable messages again that were not acked in time and forgets idle connections
+    /// does nothing before the wakeup, so a flood of packets does not walk over every map for each of them
+    pub fn resend(&mut self, now: Instant) {
+        if now < self.wakeup {
+            return;
+        }
+        if now >= self.next_housekeeping {
+            self.connections.retain(|_, connection| now.duration_since(connection.last_received) < self.idle && !connection.gave_up());
+            self.malformed.retain(|_, (_, last)| now.duration_since(*last) < self.idle);
+            self.blocked.retain(|_, until| now < *until);
+            self.version_replies.retain(|_, last| now.duration_since(*last) < VERSION_REPLY_INTERVAL);
+            self.next_housekeeping = now + HOUSEKEEPING_INTERVAL;
+        }
+        for (addr, connection) in self.connections.iter_mut() {
+            for bytes in connection.resend(now) {
+                let _ = self.socket.send_to(&bytes, addr);
+            }
+        }
+        self.wakeup = self.connections
+            .values()
+            .filter_map(|connection| connection.next_resend())
+            .fold(self.next_housekeeping, Instant::min);
+    }
+    /// when the network thread has to wake up again without any incoming traffic
+    pub fn next_wakeup(&self) -> Instant {
+        self.wakeup
+    }
+    /// decodes a single datagram, only well-formed logins and challenge responses create a connection
+    pub fn receive(&mut self, addr: SocketAddr, bytes: &[u8], now: Instant) -> Vec<ClientMessage> {
+        let mut messages = Vec::new();
+        if self.blocked.contains_key(&addr.ip()) {
+            return messages;
+        }
+        match Packet::<ClientMessage>::decode(bytes) {
+            Ok(packet) => {
+                // anything with a token belongs to a peer that already has a connection, other packets may come from forged addresses
+                if !self.connections.contains_key(&addr) && (packet.message.token().is_some() || self.connections.len() >= self.max_connections) {
+                    return messages;
+                }
+                let connection = self.connections.entry(addr).or_insert_with(|| Connection::new(now));
+                for (_, client_message) in connection.receive_packet(packet, now) {
+                    messages.push(client_message);
+                }
+            },
+            Err(e @ DecodeError::VersionMismatch(_)) => {
+                // the header is readable by every version, so the client learns which one we speak
+                // once per interval only, the sender address of the packet may be forged
+                if self.version_replies.get(&addr).is_none_or(|last| now.duration_since(*last) >= VERSION_REPLY_INTERVAL) {
+                    self.version_replies.insert(addr, now);
+                    let rejection = ServerMessage::Rejected(RejectReason::VersionMismatch { supported: PROTOCOL_VERSION });
+                    if let Ok(bytes) = Packet::unconnected(0, rejection).encode() {
+                        self.send_to(&bytes, addr);
+                    }
+                }
+                self.malformed(addr, e, now);
+            },
+            Err(e) => self.malformed(addr, e, now),
+        }
+        messages
+    }
+    fn malformed(&mut self, addr: SocketAddr, error: DecodeError, now: Instant) {
+        let host = addr.ip();
+        if !self.malformed.contains_key(&host) && self.malformed.len() >= MAX_MALFORMED_PEERS {
+            return;
+        }
+        let (count, last) = self.malformed.entry(host).or_insert((0, now));
+        *count += 1;
+        *last = now;
+        let count = *count;
+        if self.policy.log {
+            eprintln!("malformed packet number {} from {}: {}", count, addr, error);
+        }
+        if self.policy.block_threshold != 0 && count >= self.policy.block_threshold {
+            eprintln!("blocking {} for {:?} after {} malformed packets", host, BLOCK_DURATION, count);
+            self.malformed.remove(&host);
+            self.connections.retain(|peer, _| peer.ip() != host);
+            self.blocked.insert(host, now + BLOCK_DURATION);
+        }
+    }
 }
 
 #[derive(Resource)]
 pub struct IncomingReceiver(crossbeam::channel::Receiver<(SocketAddr, ClientMessage)>);
+/// sending only fails once the network thread stopped, which it only does after the app is gone
 #[derive(Resource)]
-pub struct OutgoingSender(crossbeam::channel::Sender<(SocketAddr, ServerMessage)>);
+pub struct OutgoingSender(crossbeam::channel::Sender<(SocketAddr, u32, ServerMessage)>);
 
 fn main() {
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{}", SERVER_USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}\n{}", e, SERVER_USAGE);
+            std::process::exit(2);
+        },
+    };
+    let socket = match UdpSocket::bind(config.bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("could not bind {}: {}", config.bind, e);
+            std::process::exit(1);
+        },
+    };
+    println!("listening on {}", config.bind);
+
     let (incoming_sender, incoming_receiver) = crossbeam::channel::unbounded::<(SocketAddr, ClientMessage)>();
-    let (outgoing_sender, outgoing_receiver) = crossbeam::channel::unbounded::<(SocketAddr, ServerMessage)>();
+    let (outgoing_sender, outgoing_receiver) = crossbeam::channel::unbounded::<(SocketAddr, u32, ServerMessage)>();
 
-    let network_thread = std::thread::spawn(move || {
-        let socket = UdpSocket::bind("0.0.0.0:7878").unwrap();
-        socket.set_nonblocking(true).unwrap();
-        let mut server_socket = ServerSocket::new(socket);
+    let policy = MalformedPolicy {
+        log: config.log_malformed,
+        block_threshold: config.malformed_block_threshold,
+    };
+    // longer than the game waits for a player, so a player is always removed before its connection
+    let idle = Duration::from_secs_f32(config.connection_timeout) * 3;
+    // every player and every login waiting for its challenge to be answered
+    let max_connections = config.max_players.saturating_add(MAX_PENDING_CHALLENGES);
+    // the reader blocks on the socket and the network thread on its channels, so an idle server sleeps
+    let (datagram_sender, datagram_receiver) = crossbeam::channel::unbounded::<(SocketAddr, Vec<u8>)>();
+    let reader_socket = socket.try_clone().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0; MAX_PACKET_SIZE + 1]; // one byte more so oversized datagrams are noticed instead of cut off
+        let mut errors = 0; // consecutive failed receives
         loop {
-            // get from game
-            while let Ok((addr, outgoing_package)) = outgoing_receiver.try_recv() {
-                let bytes = outgoing_package.encode();
-                server_socket.send_to(&bytes, addr);
+            match reader_socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    errors = 0;
+                    if datagram_sender.send((addr, buf[..len].to_vec())).is_err() {
+                        break;
+                    }
+                },
+                // on some platforms an unreachable peer shows up as an error on the next receive
+                Err(e) => {
+                    eprintln!("could not receive: {}", e);
+                    // a single error is normal, a socket that keeps failing must not spin the thread
+                    if errors > 0 {
+                        std::thread::sleep((RECEIVE_BACKOFF * 2u32.saturating_pow(errors - 1)).min(MAX_RECEIVE_BACKOFF));
+                    }
+                    errors += 1;
+                },
             }
+        }
+    });
 
-            // get from socket
-            let ServerSocket { socket, buf } = &mut server_socket;
-
-            while let Ok((len, addr)) = socket.recv_from(buf) {
-                if let Some(client_message) = ClientMessage::decode(buf) {
-                    incoming_sender.send((addr, client_message));
-                }
+    std::thread::spawn(move || {
+        let mut server_socket = ServerSocket::new(socket, policy, idle, max_connections);
+        loop {
+            let timeout = server_socket.next_wakeup().saturating_duration_since(Instant::now());
+            crossbeam::channel::select! {
+                // get from game
+                recv(outgoing_receiver) -> outgoing => {
+                    let Ok((addr, tick, outgoing_package)) = outgoing else {
+                        break;
+                    };
+                    server_socket.send_message(outgoing_package, tick, addr, Instant::now());
+                },
+                // get from socket
+                recv(datagram_receiver) -> datagram => {
+                    let Ok((addr, bytes)) = datagram else {
+                        break;
+                    };
+                    let messages = server_socket.receive(addr, &bytes, Instant::now());
+                    // the game is gone once nobody takes its messages anymore
+                    if messages.into_iter().any(|client_message| incoming_sender.send((addr, client_message)).is_err()) {
+                        break;
+                    }
+                },
+                default(timeout) => {},
             }
+            server_socket.resend(Instant::now());
         }
     });
 
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugins(PhysicsPlugins::default())
+    server_app(config, incoming_receiver, outgoing_sender).run();
+}
+
+/// the simulation and everything that connects it to the network thread
+fn server_app(
+    config: ServerConfig,
+    incoming_receiver: crossbeam::channel::Receiver<(SocketAddr, ClientMessage)>,
+    outgoing_sender: crossbeam::channel::Sender<(SocketAddr, u32, ServerMessage)>,
+) -> App {
+    let areas = config.layout();
+    let mut app = App::new();
+    // headless by default, the debug view opens a window that draws the simulation with gizmos
+    #[cfg(not(feature = "debug-view"))]
+    app.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1. / config.tick_rate as f64))),
+        TransformPlugin,
+        AssetPlugin::default(),
+    ));
+    #[cfg(feature = "debug-view")]
+    app.add_plugins((DefaultPlugins, avian2d::prelude::PhysicsDebugPlugin))
+        .add_systems(Startup, setup)
+        .add_systems(Update, draw_players);
+
+    app.add_plugins(GameSimulationPlugin { authoritative: true })
         .insert_resource(IncomingReceiver(incoming_receiver))
         .insert_resource(OutgoingSender(outgoing_sender))
-        .insert_resource(Gravity::ZERO)
         .insert_resource(IDCounter(0))
         .insert_resource(EntityMap::default())
         .insert_resource(NetIDMap::default())
-        .add_systems(Startup, (setup, spawn_enemies))
-        .add_systems(Update, (receive_messages, apply_velocity_system, enemy_kill_system, broadcast_enemies, broadcast_players))
-        .run();
-}
-
-// Define collision layers
-#[derive(PhysicsLayer, Clone, Copy, Debug, Default)]
-enum Layer {
-    #[default]
-    Boundary,
-    Ball,
+        .insert_resource(PendingChallenges::default())
+        .insert_resource(Sessions::default())
+        .insert_resource(ConnectionTimeout(Duration::from_secs_f32(config.connection_timeout)))
+        .insert_resource(Mtu(config.mtu))
+        .insert_resource(SnapshotCounter::default())
+        .insert_resource(Quantization::new(areas.bounds().min, areas.bounds().max, config.position_precision))
+        .insert_resource(areas)
+        .insert_resource(Time::<Fixed>::from_hz(config.tick_rate as f64))
+        .insert_resource(config)
+        .insert_resource(Tick(0))
+        .add_systems(Startup, spawn_enemies)
+        .add_systems(FixedUpdate, (advance_tick, receive_messages, reap_players, respawn_players, wake_occupied_areas).chain().before(SimulationSet))
+        .add_systems(FixedUpdate, game_over_system.after(SimulationSet))
+        .add_systems(FixedUpdate, (broadcast_enemies, broadcast_players).chain().run_if(send_tick).after(SimulationSet));
+    app
 }
 
 
@@ -94,90 +331,415 @@ struct EntityMap(HashMap<NetIDType, Entity>);
 #[derive(Resource)]
 struct IDCounter(pub NetIDType);
 
+/// number of the current simulation tick, stamped on every packet
+#[derive(Resource)]
+struct Tick(u32);
+
+fn advance_tick(
+    mut tick: ResMut<Tick>,
+) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+/// whether the current tick is one where the state is broadcast
+fn send_tick(
+    tick: Res<Tick>,
+    config: Res<ServerConfig>,
+) -> bool {
+    // the simulation runs in FixedUpdate at tick_rate, clients get updates at send_rate
+    // sent whenever the number of sends due so far goes up, so rates that do not divide each other keep their average
+    let sends = |tick: u32| tick as u64 * config.send_rate as u64 / config.tick_rate as u64;
+    sends(tick.0) != sends(tick.0.wrapping_sub(1))
+}
+
+/// display name the player logged in with
+#[derive(Component)]
+struct PlayerName(String);
+
+/// challenges sent to addresses that requested a login but did not answer yet
+#[derive(Resource, Default)]
+struct PendingChallenges(HashMap<SocketAddr, (u64, String, Duration)>); // the challenge, the requested name and when it was issued
+/// maps the token of every logged in client to its player entity
+#[derive(Resource, Default)]
+struct Sessions(HashMap<SessionToken, Entity>);
+
+/// players that did not send anything for this long get removed
+#[derive(Resource)]
+struct ConnectionTimeout(Duration);
+
+/// time of the last authenticated message of the owning client
+#[derive(Component)]
+struct LastSeen(Duration);
+
+/// net ids of the enemies the client currently knows about
+#[derive(Component, Default)]
+struct InterestSet(HashSet<NetIDType>);
+
+/// sequence of the newest input applied to the player, players only move by their inputs
+#[derive(Component)]
+struct LastInput(Option<u32>);
+
+/// server time the inputs of the player add up to, inputs covering more time than has passed are rejected
+#[derive(Component)]
+struct InputClock(Duration);
+
+/// the enemy snapshots sent to the client, used as baselines for delta compression
+#[derive(Component, Default)]
+struct SnapshotHistory {
+    sent: VecDeque<(u32, HashMap<NetIDType, EnemyPackage>)>,
+    acked: Option<u32>,
+}
+
+/// id of the next enemy snapshot
+#[derive(Resource, Default)]
+struct SnapshotCounter(u32);
+
+/// the client asked to leave, the player gets removed by reap_players
+#[derive(Component)]
+struct Disconnecting;
+
+/// the client asked to play again, handled by respawn_players
+#[derive(Component)]
+struct WantsRespawn;
+
+/// when the player died, the Delayed respawn policy counts from here
+#[derive(Component)]
+struct DiedAt(Duration);
+
+/// returns the player entity owning the token if the message really comes from its address
+fn authenticate(
+    sessions: &Sessions,
+    addresses: &Query<&UpdateAddress>,
+    token: SessionToken,
+    addr: SocketAddr,
+) -> Option<Entity> {
+    let player_entity = *sessions.0.get(&token)?;
+    let update_address = addresses.get(player_entity).ok()?;
+    // a valid token sent from another address is treated like a forged one
+    (update_address.addr == addr).then_some(player_entity)
+}
+
 
 fn receive_messages(
+    time: Res<Time>,
+    tick: Res<Tick>,
+    config: Res<ServerConfig>,
+    (quantization, areas): (Res<Quantization>, Res<Areas>),
     incoming_receiver: Res<IncomingReceiver>,
     outgoing_sender: Res<OutgoingSender>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
     mut id_counter: ResMut<IDCounter>,
     mut net_id_map: ResMut<NetIDMap>,
     mut entity_map: ResMut<EntityMap>,
-    mut player_query: Query<&mut Velocity, With<Player>>,
+    mut pending_challenges: ResMut<PendingChallenges>,
+    mut sessions: ResMut<Sessions>,
+    mtu: Res<Mtu>,
+    addresses: Query<&UpdateAddress>,
+    names: Query<(Entity, &UpdateAddress, &PlayerName)>,
+    mut player_query: Query<(&mut Transform, &mut Velocity, &mut LastInput, &mut InputClock, &mut LastSeen, &mut SnapshotHistory, &PlayerState), With<Player>>,
 ) {
+    let mut joined = Vec::new();
     while let Ok((addr, client_message)) = incoming_receiver.0.try_recv() {
+        let player_entity = client_message
+            .token()
+            .and_then(|token| authenticate(&sessions, &addresses, token, addr));
+        if let Some(Ok((_, _, _, _, mut last_seen, _, _))) = player_entity.map(|entity| player_query.get_mut(entity)) {
+            last_seen.0 = time.elapsed();
+        }
+
         match client_message {
-            ClientMessage::Login => {
+            ClientMessage::Login(name) => {
+                // the player is only created once the client proves it can receive on this address
+                // a repeated login gets the same challenge, so the answer to any copy of it is accepted
+                let (challenge, issued) = match pending_challenges.0.get(&addr) {
+                    Some((challenge, _, issued)) => (*challenge, *issued),
+                    // forged addresses never answer, so their logins must not pile up
+                    None if pending_challenges.0.len() >= MAX_PENDING_CHALLENGES => continue,
+                    None => (random_token(), time.elapsed()),
+                };
+                pending_challenges.0.insert(addr, (challenge, sanitize_name(&name), issued));
+                let _ = outgoing_sender.0.send((addr, tick.0, ServerMessage::Challenge(challenge)));
+            },
+            ClientMessage::ChallengeResponse(challenge) => {
+                if pending_challenges.0.get(&addr).is_none_or(|(pending, _, _)| *pending != challenge) {
+                    continue;
+                }
+                let Some((_, name, _)) = pending_challenges.0.remove(&addr) else {
+                    continue;
+                };
+
+                if sessions.0.len() >= config.max_players {
+                    println!("rejected {}, the server is full", addr);
+                    let _ = outgoing_sender.0.send((addr, tick.0, ServerMessage::Rejected(RejectReason::ServerFull)));
+                    continue;
+                }
+
+                let token = random_token();
                 let id = commands.spawn((
-                    Transform::from_xyz(200., 0., 1.),
-                    Player,
-                    Alive(true),
-                    Radius(20.),
-                    Velocity(Vec2::new(-200., 0.)),
-                    Mesh2d(meshes.add(Circle::new(20.))),
-                    MeshMaterial2d(materials.add(Color::srgb(0., 1., 0.))),
+                    player_bundle(areas.0[0].start()),
+                    PlayerName(name.clone()),
                     UpdateAddress {addr},
+                    LastSeen(time.elapsed()),
+                    LastInput(None),
+                    InputClock(time.elapsed()),
+                    InterestSet::default(),
+                    SnapshotHistory::default(),
                 )).id();
 
                 net_id_map.0.insert(id, id_counter.0);
                 entity_map.0.insert(id_counter.0, id);
-                outgoing_sender.0.send((addr, ServerMessage::Ok(id_counter.0)));
+                sessions.0.insert(token, id);
+                let _ = outgoing_sender.0.send((addr, tick.0, ServerMessage::Ok(id_counter.0, token, ServerInfo {
+                    quantization: *quantization,
+                    tick_rate: config.tick_rate,
+                    areas: areas.0.clone(),
+                })));
+                println!("{} joined as {:?} with id {}", addr, name, id_counter.0);
+                joined.push((addr, PlayerInfo { net_id: id_counter.0, name }));
 
                 id_counter.0 += 1;
             },
-            ClientMessage::SetVelocity(player_net_id, velocity) => {
-                let player_entity_option = entity_map.0.get(&player_net_id);
-                let mut player_exists = false;
-                match player_entity_option {
-                    Some(player_entity) => {
-                        let mut player_velocity_result = player_query.get_mut(*player_entity);
-                        match player_velocity_result {
-                            Ok(mut player_velocity) => {
-                                player_exists = true;
-                                player_velocity.0 = velocity.into();
-                            },
-                            Err(_) => {},
-                        }
-                    },
-                    None => {},
+            ClientMessage::SetVelocity(_, input) => {
+                let Some(player_entity) = player_entity else {
+                    continue;
+                };
+                if let Ok((mut transform, mut player_velocity, mut last_input, mut input_clock, _, _, state)) = player_query.get_mut(player_entity) {
+                    // inputs arrive unreliably, an old one must not move the player again
+                    if last_input.0.is_some_and(|last| input.sequence <= last) {
+                        continue;
+                    }
+                    // a client may neither move faster than the player can nor claim more time than has passed
+                    let velocity = Vec2::from(input.velocity);
+                    let velocity = if velocity.is_finite() { velocity.clamp_length_max(PLAYER_SPEED) } else { Vec2::ZERO };
+                    let input = PlayerInput { velocity: velocity.into(), ..input };
+                    let dt = Duration::from_secs_f32(input.dt.clamp(0., MAX_INPUT_DT));
+                    // time the client did not use is only kept up to the tolerance, so it cannot be saved up for a burst
+                    let clock = input_clock.0.max(time.elapsed().saturating_sub(INPUT_CLOCK_TOLERANCE)) + dt;
+                    let in_time = clock <= time.elapsed() + INPUT_CLOCK_TOLERANCE;
+                    if in_time {
+                        input_clock.0 = clock;
+                    }
+                    // still acked while not alive or too fast, so the client prediction snaps back to where the player is
+                    if *state == PlayerState::Alive && in_time {
+                        input.apply(&mut transform.translation);
+                        player_velocity.0 = input.velocity.into();
+                    }
+                    last_input.0 = Some(input.sequence);
+                }
+            },
+            ClientMessage::AckSnapshot(_, snapshot) => {
+                let Some(player_entity) = player_entity else {
+                    continue;
+                };
+                if let Ok((_, _, _, _, _, mut snapshot_history, _)) = player_query.get_mut(player_entity) {
+                    // acks can arrive out of order, only ever move the baseline forward
+                    if snapshot_history.acked.is_none_or(|acked| snapshot > acked) {
+                        snapshot_history.acked = Some(snapshot);
+                    }
                 }
-                if !player_exists {
-                    entity_map.0.remove(&player_net_id);
+            },
+            ClientMessage::Heartbeat(_) => {},
+            ClientMessage::Disconnect(_) => {
+                if let Some(player_entity) = player_entity {
+                    commands.entity(player_entity).insert(Disconnecting);
+                }
+            },
+            ClientMessage::Respawn(_) => {
+                if let Some(player_entity) = player_entity {
+                    commands.entity(player_entity).insert(WantsRespawn);
                 }
             },
         }
     }
+
+    if joined.is_empty() {
+        return;
+    }
+
+    // the new players learn the names of everyone, the others only those of the new ones
+    let mut everyone: Vec<PlayerInfo> = names
+        .iter()
+        .filter_map(|(entity, _, name)| Some(PlayerInfo { net_id: *net_id_map.0.get(&entity)?, name: name.0.clone() }))
+        .collect();
+    everyone.extend(joined.iter().map(|(_, info)| info.clone()));
+
+    let mut builder = PacketBuilder::new(mtu.0, ServerMessage::PlayersJoined);
+    builder.extend(joined.iter().map(|(_, info)| info.clone()));
+    let joined_messages = builder.finish();
+    for (_, update_address, _) in &names {
+        for message in &joined_messages {
+            let _ = outgoing_sender.0.send((update_address.addr, tick.0, message.clone()));
+        }
+    }
+
+    let mut builder = PacketBuilder::new(mtu.0, ServerMessage::PlayersJoined);
+    builder.extend(everyone);
+    let everyone_messages = builder.finish();
+    for (addr, _) in &joined {
+        for message in &everyone_messages {
+            let _ = outgoing_sender.0.send((*addr, tick.0, message.clone()));
+        }
+    }
 }
 
-const ENEMIES_PER_PACKAGE: usize = (1000. / std::mem::size_of::<EnemyPackage>() as f32).floor() as usize;
-const PLAYERS_PER_PACKAGE: usize = (1000. / std::mem::size_of::<PlayerPackage>() as f32).floor() as usize;
+fn reap_players(
+    time: Res<Time>,
+    tick: Res<Tick>,
+    timeout: Res<ConnectionTimeout>,
+    outgoing_sender: Res<OutgoingSender>,
+    mut commands: Commands,
+    mut net_id_map: ResMut<NetIDMap>,
+    mut entity_map: ResMut<EntityMap>,
+    mut sessions: ResMut<Sessions>,
+    mut pending_challenges: ResMut<PendingChallenges>,
+    player_query: Query<(Entity, &LastSeen, &UpdateAddress, Has<Disconnecting>), With<Player>>,
+) {
+    pending_challenges.0.retain(|_, (_, _, issued)| time.elapsed().saturating_sub(*issued) < CHALLENGE_TIMEOUT);
+
+    let mut left = Vec::new();
+    for (player_entity, last_seen, update_address, disconnecting) in &player_query {
+        if !disconnecting && time.elapsed().saturating_sub(last_seen.0) < timeout.0 {
+            continue;
+        }
+        commands.entity(player_entity).despawn();
+        sessions.0.retain(|_, entity| *entity != player_entity);
+        if let Some(net_id) = net_id_map.0.remove(&player_entity) {
+            entity_map.0.remove(&net_id);
+            left.push((net_id, update_address.addr));
+        }
+    }
+
+    // tell everyone who stays so they can drop the player on their side
+    for (net_id, _) in &left {
+        for (_, _, update_address, _) in &player_query {
+            if left.iter().any(|(_, addr)| *addr == update_address.addr) {
+                continue;
+            }
+            let _ = outgoing_sender.0.send((update_address.addr, tick.0, ServerMessage::PlayerLeft(*net_id)));
+        }
+    }
+}
+
+/// brings dead players that asked for it back to the start of their area once the respawn policy allows it
+fn respawn_players(
+    time: Res<Time>,
+    config: Res<ServerConfig>,
+    areas: Res<Areas>,
+    mut commands: Commands,
+    mut player_query: Query<(Entity, &mut PlayerState, &mut PlayerStats, &mut Transform, &mut Velocity, Option<&DiedAt>, Has<WantsRespawn>), With<Player>>,
+) {
+    let is_dead = |state: &PlayerState| matches!(state, PlayerState::Dead(_) | PlayerState::Spectating);
+    let team_wiped = player_query.iter().all(|(_, state, ..)| is_dead(state));
+    // the whole team starts over together as soon as one of them asks
+    let everyone = config.respawn == RespawnPolicy::TeamWiped && player_query.iter().any(|(.., wants_respawn)| wants_respawn);
+
+    for (player_entity, mut state, mut stats, mut transform, mut velocity, died_at, wants_respawn) in &mut player_query {
+        if wants_respawn {
+            commands.entity(player_entity).remove::<WantsRespawn>();
+        }
+        if !is_dead(&state) || !(wants_respawn || everyone) {
+            continue;
+        }
+        let allowed = match config.respawn {
+            RespawnPolicy::Instant => true,
+            RespawnPolicy::Delayed => died_at.is_none_or(|died_at| time.elapsed().saturating_sub(died_at.0).as_secs_f32() >= config.respawn_delay),
+            RespawnPolicy::TeamWiped => team_wiped,
+        };
+        if !allowed {
+            continue;
+        }
+
+        *state = PlayerState::Alive;
+        *stats = PlayerStats::default();
+        let area = &areas.0[areas.index_at(transform.translation.truncate())];
+        transform.translation = area.start().extend(transform.translation.z);
+        velocity.0 = Vec2::ZERO;
+        commands.entity(player_entity).remove::<DiedAt>();
+    }
+}
+
+/// enemies of areas without players are frozen and left out of the physics, the spatial grid and the broadcasts until someone comes back
+fn wake_occupied_areas(
+    areas: Res<Areas>,
+    mut commands: Commands,
+    mut occupied: Local<Vec<bool>>,
+    players: Query<&Transform, With<Player>>,
+    enemies: Query<(Entity, &InArea), With<Enemy>>,
+) {
+    let mut now_occupied = vec![false; areas.0.len()];
+    for transform in &players {
+        now_occupied[areas.index_at(transform.translation.truncate())] = true;
+    }
+    if *occupied == now_occupied {
+        return;
+    }
+
+    for (enemy, area) in &enemies {
+        if now_occupied[area.0] {
+            commands.entity(enemy).remove::<(RigidBodyDisabled, ColliderDisabled)>();
+        }
+        else {
+            commands.entity(enemy).insert((RigidBodyDisabled, ColliderDisabled));
+        }
+    }
+    *occupied = now_occupied;
+}
+
+/// tells players how they did as soon as they are dead for good
+fn game_over_system(
+    time: Res<Time>,
+    tick: Res<Tick>,
+    outgoing_sender: Res<OutgoingSender>,
+    mut commands: Commands,
+    mut deaths: MessageReader<PlayerDied>,
+    player_query: Query<(&UpdateAddress, &PlayerStats, &PlayerName), With<Player>>,
+) {
+    for PlayerDied(player_entity) in deaths.read() {
+        let Ok((update_address, stats, name)) = player_query.get(*player_entity) else {
+            continue;
+        };
+        println!("{:?} died after {:.1} seconds", name.0, stats.survival_time);
+        commands.entity(*player_entity).insert(DiedAt(time.elapsed()));
+        let _ = outgoing_sender.0.send((update_address.addr, tick.0, ServerMessage::GameOver(*stats)));
+    }
+}
+
+/// encoded size budget of a single outgoing datagram
+#[derive(Resource)]
+struct Mtu(usize);
 
 fn broadcast_enemies(
+    tick: Res<Tick>,
+    config: Res<ServerConfig>,
+    mtu: Res<Mtu>,
+    quantization: Res<Quantization>,
     outgoing_sender: Res<OutgoingSender>,
-    client_addresses: Query<(Entity, &UpdateAddress, &Transform)>,
-    enemy_query: Query<(Entity, &Transform, &Radius), With<Enemy>>,
-    mut net_id_map: ResMut<NetIDMap>,
+    mut snapshot_counter: ResMut<SnapshotCounter>,
+    client_addresses: Query<(&UpdateAddress, &Transform, &mut InterestSet, &mut SnapshotHistory)>,
+    grid: Res<SpatialGrid>,
+    net_id_map: Res<NetIDMap>,
 ) {
-    const BROADCAST_RADIUS: f32 = 500.0;
-    const RADIUS_SQUARED: f32 = BROADCAST_RADIUS * BROADCAST_RADIUS; // Avoid sqrt in distance checks
+    let radius_squared = config.interest_radius * config.interest_radius; // Avoid sqrt in distance checks
 
     // Process each client separately
-    for (id, addr, player_transform) in client_addresses.iter() {
-        let player_pos = player_transform.translation;
+    let snapshot = snapshot_counter.0;
+    snapshot_counter.0 = snapshot_counter.0.wrapping_add(1);
+
+    for (addr, player_transform, mut interest_set, mut snapshot_history) in client_addresses {
+        let player_pos = player_transform.translation.truncate();
         
-        // Collect enemies within radius for this specific player
-        let mut nearby_enemies: Vec<EnemyPackage> = enemy_query
-            .iter()
-            .filter_map(|(enemy_entity, enemy_transform, radius)| {
-                let distance_squared = player_pos.distance_squared(enemy_transform.translation);
+        // Collect enemies within radius for this specific player, the grid only hands out the ones in nearby cells
+        let nearby_enemies: Vec<EnemyPackage> = grid
+            .query(player_pos, config.interest_radius)
+            .filter_map(|(enemy_entity, enemy_position, radius)| {
+                let distance_squared = player_pos.distance_squared(enemy_position);
                 
-                if distance_squared <= RADIUS_SQUARED {
+                if distance_squared <= radius_squared {
                     let net_id = net_id_map.0.get(&enemy_entity)?;
                     Some(EnemyPackage {
                         net_id: *net_id,
-                        position: enemy_transform.translation.into(),
-                        radius: radius.0,
+                        position: quantization.quantize(enemy_position),
+                        radius,
                     })
                 } else {
                     None
@@ -185,50 +747,104 @@ fn broadcast_enemies(
             })
             .collect();
 
-        // Split into chunks and send
-        for enemy_chunk in nearby_enemies.chunks(ENEMIES_PER_PACKAGE) {
-            let message = ServerMessage::UpdateEnemies(enemy_chunk.to_vec());
-            outgoing_sender.0.send((addr.addr, message));
+        // compare against what the client saw last time
+        let nearby_ids: HashSet<NetIDType> = nearby_enemies.iter().map(|enemy| enemy.net_id).collect();
+        let left: Vec<NetIDType> = interest_set.0.difference(&nearby_ids).copied().collect();
+        let entered_enemies: Vec<EnemyPackage> = nearby_enemies
+            .iter()
+            .filter(|enemy| !interest_set.0.contains(&enemy.net_id))
+            .cloned()
+            .collect();
+        interest_set.0 = nearby_ids;
+
+        // diff against the last snapshot the client acked, or send everything if it is not known anymore
+        let baseline = snapshot_history.acked.and_then(|acked| {
+            snapshot_history.sent.iter().find(|(sent, _)| *sent == acked)
+        });
+        let baseline_id = baseline.map(|(sent, _)| *sent);
+        let deltas: Vec<EnemyDelta> = nearby_enemies
+            .iter()
+            .map(|enemy| EnemyDelta::diff(baseline.and_then(|(_, enemies)| enemies.get(&enemy.net_id)), enemy))
+            .collect();
+
+        // Split into datagrams and send
+        let mut left_builder = PacketBuilder::new(mtu.0, ServerMessage::EntitiesLeft);
+        left_builder.extend(left);
+        let mut entered_builder = PacketBuilder::new(mtu.0, ServerMessage::EnemiesEntered);
+        entered_builder.extend(entered_enemies);
+        let mut update_builder = PacketBuilder::new(mtu.0, |enemies| ServerMessage::UpdateEnemies(EnemySnapshot {
+            snapshot,
+            baseline: baseline_id,
+            fragment: 0,
+            fragment_count: 0,
+            enemies,
+        }));
+        update_builder.extend(deltas);
+
+        // fragment fields are fixed size so filling them in afterwards does not change the encoded size
+        let mut updates = update_builder.finish();
+        updates.truncate(u8::MAX as usize);
+        let fragment_count = updates.len() as u8;
+        for (fragment, message) in updates.iter_mut().enumerate() {
+            if let ServerMessage::UpdateEnemies(enemy_snapshot) = message {
+                enemy_snapshot.fragment = fragment as u8;
+                enemy_snapshot.fragment_count = fragment_count;
+            }
+        }
+
+        snapshot_history.sent.push_back((
+            snapshot,
+            nearby_enemies.into_iter().map(|enemy| (enemy.net_id, enemy)).collect(),
+        ));
+        if snapshot_history.sent.len() > SNAPSHOT_HISTORY {
+            snapshot_history.sent.pop_front();
+        }
+
+        for builder in [left_builder.finish(), entered_builder.finish(), updates] {
+            for message in builder {
+                let _ = outgoing_sender.0.send((addr.addr, tick.0, message));
+            }
         }
     }
 }
 
 fn broadcast_players(
+    tick: Res<Tick>,
+    mtu: Res<Mtu>,
+    quantization: Res<Quantization>,
     outgoing_sender: Res<OutgoingSender>,
-    client_addresses: Query<(Entity, &UpdateAddress)>,
-    player_query: Query<(Entity, &Transform), With<Player>>,
-    mut net_id_map: ResMut<NetIDMap>,
+    client_addresses: Query<(&UpdateAddress, &Transform, &LastInput)>,
+    player_query: Query<(Entity, &Transform, &PlayerState), With<Player>>,
+    net_id_map: Res<NetIDMap>,
 ) {
-    let player_package_vec_count = (player_query.iter().len() as f32 / PLAYERS_PER_PACKAGE as f32).ceil() as usize;
-    let mut player_package_vec = Vec::<Vec<PlayerPackage>>::new();
-    let mut player_packages: Vec<PlayerPackage> = Vec::with_capacity(PLAYERS_PER_PACKAGE);
-    let mut counter = 0;
-    for (player_entity, player_transform) in player_query {
-        let net_id = net_id_map.0.get(&player_entity).unwrap();
-        player_packages.push(PlayerPackage {
-            net_id: *net_id,
-            position: player_transform.translation.into(),
-        });
-        counter += 1;
-        if counter >= PLAYERS_PER_PACKAGE {
-            counter = 0;
-            player_package_vec.push(player_packages);
-            player_packages = Vec::with_capacity(PLAYERS_PER_PACKAGE);
+    // lets each client correct its predicted position
+    for (addr, transform, last_input) in client_addresses {
+        if let Some(last_input) = last_input.0 {
+            let position = quantization.quantize(transform.translation.truncate());
+            let _ = outgoing_sender.0.send((addr.addr, tick.0, ServerMessage::InputAck(last_input, position)));
         }
     }
-    if player_packages.len() > 0 {
-        player_package_vec.push(player_packages);
-    }
 
-    for player_packages in player_package_vec {
-        let message = ServerMessage::UpdatePlayers(player_packages);
+    let mut builder = PacketBuilder::new(mtu.0, ServerMessage::UpdatePlayers);
+    for (player_entity, player_transform, state) in player_query {
+        let Some(net_id) = net_id_map.0.get(&player_entity) else {
+            continue;
+        };
+        builder.push(PlayerPackage {
+            net_id: *net_id,
+            position: quantization.quantize(player_transform.translation.truncate()),
+            state: *state,
+        });
+    }
 
-        for (id, addr) in client_addresses {
-            outgoing_sender.0.send((addr.addr, message.clone()));
+    for message in builder.finish() {
+        for (addr, _, _) in client_addresses {
+            let _ = outgoing_sender.0.send((addr.addr, tick.0, message.clone()));
         }
     }
 }
 
+#[cfg(feature = "debug-view")]
 fn setup(
     mut commands: Commands,
 ) {
@@ -245,94 +861,319 @@ fn setup(
     ));
 }
 
+/// colors the players by their state, the physics debug view only shows their sensor outline
+#[cfg(feature = "debug-view")]
+fn draw_players(
+    mut gizmos: Gizmos,
+    players: Query<(&Transform, &Radius, &PlayerState), With<Player>>,
+) {
+    for (transform, radius, state) in players {
+        let color = match state {
+            PlayerState::Alive => Color::srgb(0., 1., 0.),
+            PlayerState::Downed(_) => Color::srgb(1., 1., 0.),
+            PlayerState::Dead(_) => Color::srgb(1., 0., 0.),
+            PlayerState::Spectating => continue,
+        };
+        gizmos.circle_2d(transform.translation.truncate(), radius.0, color);
+    }
+}
+
 fn spawn_enemies(
+    config: Res<ServerConfig>,
+    areas: Res<Areas>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
     mut id_counter: ResMut<IDCounter>,
     mut net_id_map: ResMut<NetIDMap>,
     mut entity_map: ResMut<EntityMap>,
 ) {
-    let mut rng = rand::rng();
-    // + Spawn static boundary colliders
-    let half_boundary = 3000.0;
-    let thickness = 10.0;
-    let wall_material = MeshMaterial2d(materials.add(Color::srgb(
-        rng.random_range(0.0..4.0),
-        rng.random_range(0.0..4.0),
-        rng.random_range(0.0..4.0),
-    )));
-    for &pos in &[-half_boundary, half_boundary] {
-        // vertical walls
-        commands.spawn((
-            Mesh2d(meshes.add(Rectangle::new(thickness, half_boundary * 2.))),
-            wall_material.clone(),
-            Transform::from_xyz(pos, 0., 0.),
-            RigidBody::Static,
-            Collider::rectangle(thickness, half_boundary * 2.),
-            CollisionLayers::new([Layer::Boundary], [Layer::Ball]),
-        ));
-        // horizontal walls
-        commands.spawn((
-            Mesh2d(meshes.add(Rectangle::new(half_boundary * 2., thickness))),
-            wall_material.clone(),
-            Transform::from_xyz(0., pos, 0.),
-            RigidBody::Static,
-            Collider::rectangle(half_boundary * 2., thickness),
-            CollisionLayers::new([Layer::Boundary], [Layer::Ball]),
-        ));
-    }
+    for (index, (area, area_config)) in areas.0.iter().zip(&config.areas).enumerate() {
+        // + Spawn static boundary colliders
+        for (position, size) in area.walls() {
+            commands.spawn(wall_bundle(position, size));
+        }
+        for zone in area.safe_zones() {
+            commands.spawn(safe_zone_bundle(zone));
+        }
+
+        let speed = area_config.enemy_speed_min..=area_config.enemy_speed_max;
+        let spawn_region = area.enemy_spawn_region(area_config.enemy_radius);
+        for _ in 0..area_config.enemy_count {
+            let position = random_position_in(spawn_region);
+            let id = commands.spawn((
+                enemy_bundle(position, random_velocity(speed.clone()), area_config.enemy_radius),
+                InArea(index),
+            )).id();
 
-    for _ in 0..5000 {
-        let velocity = Velocity(random_velocity());
-        let position = random_position(2000.);
-        let material = MeshMaterial2d(materials.add(Color::srgb(
-            rng.random_range(0.0..4.0),
-            rng.random_range(0.0..4.0),
-            rng.random_range(0.0..4.0),
-        )));
-
-        // Circle mesh
-        let id = commands.spawn((
-            Transform::from_translation(position.extend(0.)),
-            Mesh2d(meshes.add(Circle::new(ENEMY_RADIUS))),
-            material,
-            RigidBody::Dynamic,
-            Collider::circle(ENEMY_RADIUS),
-            LinearVelocity(velocity.0),
-            CollisionLayers::new([Layer::Ball], [Layer::Boundary]),
-            Restitution::new(1.0), // Perfect bounce (1.0 = 100% energy retained)
-            Friction::ZERO.with_combine_rule(CoefficientCombine::Min), // Remove friction
-            Enemy,
-            Radius(ENEMY_RADIUS),
-        )).id();
-
-        net_id_map.0.insert(id, id_counter.0);
-        entity_map.0.insert(id_counter.0, id);
-        id_counter.0 += 1;
+            net_id_map.0.insert(id, id_counter.0);
+            entity_map.0.insert(id_counter.0, id);
+            id_counter.0 += 1;
+        }
     }
 }
 
-fn apply_velocity_system(
-    time: Res<Time>,
-    query: Query<(&mut Transform, &Velocity)>,
-) {
-    let d = time.delta_secs();
-    for (mut transform, velocity) in query {
-        transform.translation += velocity.0.extend(0.) * d;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::time::TimeUpdateStrategy;
+
+    /// the headless app with a manual clock, every update advances it by exactly one tick
+    fn test_app(config: ServerConfig) -> (App, crossbeam::channel::Sender<(SocketAddr, ClientMessage)>, crossbeam::channel::Receiver<(SocketAddr, u32, ServerMessage)>) {
+        let (incoming_sender, incoming_receiver) = crossbeam::channel::unbounded();
+        let (outgoing_sender, outgoing_receiver) = crossbeam::channel::unbounded();
+        let tick = Duration::from_secs_f64(1. / config.tick_rate as f64);
+        let mut app = server_app(config, incoming_receiver, outgoing_sender);
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+        app.finish();
+        app.cleanup();
+        // the first frames only start the clock
+        while app.world().resource::<Tick>().0 == 0 {
+            app.update();
+        }
+        (app, incoming_sender, outgoing_receiver)
     }
-}
 
-fn enemy_kill_system(
-    players: Query<(&mut Alive, &Transform, &Radius), With<Player>>,
-    enemies: Query<(&Transform, &Radius), With<Enemy>>,
-) {
-    for (mut player_alive, player_pos, player_radius) in players {
-        for (enemy_pos, enemy_radius) in enemies {
-            let distance = player_pos.translation.distance(enemy_pos.translation);
-            if distance - player_radius.0 - enemy_radius.0 <= 0. {
-                player_alive.0 = false;
+    /// few enemies so tests can run for many ticks
+    fn small_config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        for area in &mut config.areas {
+            area.enemy_count = 10;
+        }
+        config
+    }
+
+    /// logs a client in through the handshake and returns its session and player
+    fn join(
+        app: &mut App,
+        incoming: &crossbeam::channel::Sender<(SocketAddr, ClientMessage)>,
+        outgoing: &crossbeam::channel::Receiver<(SocketAddr, u32, ServerMessage)>,
+        addr: SocketAddr,
+    ) -> (SessionToken, Entity) {
+        incoming.send((addr, ClientMessage::Login("tester".into()))).unwrap();
+        app.update();
+        let challenge = outgoing
+            .try_iter()
+            .find_map(|(to, _, message)| match message {
+                ServerMessage::Challenge(challenge) if to == addr => Some(challenge),
+                _ => None,
+            })
+            .expect("no challenge sent");
+
+        incoming.send((addr, ClientMessage::ChallengeResponse(challenge))).unwrap();
+        app.update();
+        let token = outgoing
+            .try_iter()
+            .find_map(|(to, _, message)| match message {
+                ServerMessage::Ok(_, token, _) if to == addr => Some(token),
+                _ => None,
+            })
+            .expect("login not accepted");
+        (token, app.world().resource::<Sessions>().0[&token])
+    }
+
+    #[test]
+    fn headless_server_ticks_and_accepts_a_player() {
+        let (mut app, incoming, outgoing) = test_app(ServerConfig::default());
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        for _ in 0..5 {
+            app.update();
+        }
+        assert!(app.world().resource::<Tick>().0 > 5, "FixedUpdate does not run every frame");
+
+        join(&mut app, &incoming, &outgoing, addr);
+        for _ in 0..10 {
+            app.update();
+        }
+        assert!(outgoing.try_iter().any(|(to, _, message)| to == addr && matches!(message, ServerMessage::UpdatePlayers(..))));
+        assert_eq!(app.world().resource::<Sessions>().0.len(), 1);
+    }
+
+    #[test]
+    fn messages_need_the_token_and_the_address_of_the_player() {
+        let (mut app, incoming, outgoing) = test_app(small_config());
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let spoofer: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let (token, player) = join(&mut app, &incoming, &outgoing, addr);
+        let position = |app: &App| app.world().get::<Transform>(player).unwrap().translation.truncate();
+        let start = position(&app);
+
+        let input = |sequence, token| ClientMessage::SetVelocity(token, PlayerInput { sequence, dt: MAX_INPUT_DT, velocity: Vec2::new(0., PLAYER_SPEED).into() });
+        // the right token from another address, then a wrong token from the right address
+        incoming.send((spoofer, input(0, token))).unwrap();
+        incoming.send((spoofer, ClientMessage::Disconnect(token))).unwrap();
+        incoming.send((addr, input(1, token.wrapping_add(1)))).unwrap();
+        incoming.send((addr, ClientMessage::Disconnect(token.wrapping_add(1)))).unwrap();
+        for _ in 0..5 {
+            app.update();
+        }
+        assert!(app.world().get_entity(player).is_ok(), "a forged disconnect removed the player");
+        assert_eq!(position(&app), start, "a forged input moved the player");
+
+        // the player itself is still in control
+        incoming.send((addr, input(2, token))).unwrap();
+        app.update();
+        assert!(position(&app).y > start.y);
+    }
+
+    #[test]
+    fn inputs_are_limited_to_player_speed_and_elapsed_time() {
+        let (mut app, incoming, outgoing) = test_app(small_config());
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let (token, player) = join(&mut app, &incoming, &outgoing, addr);
+        let position = |app: &App| app.world().get::<Transform>(player).unwrap().translation.truncate();
+        let start = position(&app);
+
+        // straight up through the safe zone, where no enemy can down the player
+        let input = |sequence, speed| ClientMessage::SetVelocity(token, PlayerInput { sequence, dt: MAX_INPUT_DT, velocity: Vec2::new(0., speed).into() });
+        incoming.send((addr, input(0, 1e6))).unwrap();
+        app.update();
+        let moved = position(&app) - start;
+        assert!(moved.length() <= PLAYER_SPEED * MAX_INPUT_DT + 0.01, "moved {} in a single input", moved);
+
+        // ten seconds of inputs at once, only about the tolerance on both sides of the clock is accepted
+        for sequence in 1..=100 {
+            incoming.send((addr, input(sequence, PLAYER_SPEED))).unwrap();
+        }
+        app.update();
+        let moved = position(&app) - start;
+        let allowed = PLAYER_SPEED * (MAX_INPUT_DT + 2. * INPUT_CLOCK_TOLERANCE.as_secs_f32() + 0.1);
+        assert!(moved.y >= PLAYER_SPEED * INPUT_CLOCK_TOLERANCE.as_secs_f32() - 1., "inputs within the tolerance were rejected, moved {}", moved);
+        assert!(moved.y <= allowed, "moved {} but at most {} is possible", moved, allowed);
+    }
+
+    #[test]
+    fn pending_challenges_expire_and_are_capped() {
+        let (mut app, incoming, _outgoing) = test_app(small_config());
+        for port in 0..MAX_PENDING_CHALLENGES as u16 + 10 {
+            incoming.send((SocketAddr::from(([10, 0, 0, 1], port)), ClientMessage::Login("forged".into()))).unwrap();
+        }
+        app.update();
+        assert_eq!(app.world().resource::<PendingChallenges>().0.len(), MAX_PENDING_CHALLENGES);
+
+        let tick_rate = app.world().resource::<ServerConfig>().tick_rate;
+        for _ in 0..CHALLENGE_TIMEOUT.as_secs() as u32 * tick_rate + 2 {
+            app.update();
+        }
+        assert!(app.world().resource::<PendingChallenges>().0.is_empty());
+    }
+
+    #[test]
+    fn only_handshakes_open_connections_up_to_the_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server_socket = ServerSocket::new(socket, MalformedPolicy { log: false, block_threshold: 0 }, Duration::from_secs(30), 16);
+        let now = Instant::now();
+        let packet = |message| Connection::<ClientMessage, ServerMessage>::new(now).send(message, 0, now).unwrap();
+        let addr = |host| SocketAddr::from(([10, 0, 0, host], 4000));
+
+        // a token from an unknown address is either forged or from a connection that is long gone
+        let input = PlayerInput { sequence: 0, dt: MAX_INPUT_DT, velocity: Vec2::ZERO.into() };
+        assert!(server_socket.receive(addr(0), &packet(ClientMessage::SetVelocity(7, input)), now).is_empty());
+        assert!(server_socket.receive(addr(0), &packet(ClientMessage::Disconnect(7)), now).is_empty());
+        server_socket.send_message(ServerMessage::PlayerLeft(0), 0, addr(0), now);
+        assert!(server_socket.connections.is_empty());
+
+        for host in 0..20 {
+            server_socket.receive(addr(host), &packet(ClientMessage::Login("forged".into())), now);
+        }
+        assert_eq!(server_socket.connections.len(), 16);
+        assert!(server_socket.receive(addr(19), &packet(ClientMessage::ChallengeResponse(1)), now).is_empty());
+        // known peers are not affected by the limit
+        assert_eq!(server_socket.receive(addr(0), &packet(ClientMessage::Heartbeat(7)), now).len(), 1);
+    }
+
+    #[test]
+    fn malformed_packets_block_the_whole_host() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server_socket = ServerSocket::new(socket, MalformedPolicy { log: false, block_threshold: 3 }, Duration::from_secs(30), 16);
+        let now = Instant::now();
+        let login = Connection::<ClientMessage, ServerMessage>::new(now).send(ClientMessage::Login("tester".into()), 0, now).unwrap();
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+
+        assert_eq!(server_socket.receive(addr(4000), &login, now).len(), 1);
+        for port in 4001..4004 {
+            server_socket.receive(addr(port), &[0; 4], now);
+        }
+        assert!(server_socket.blocked.contains_key(&addr(4000).ip()));
+        assert!(server_socket.connections.is_empty());
+        // another port does not get around the block
+        assert!(server_socket.receive(addr(5000), &login, now).is_empty());
+
+        for host in 0..MAX_MALFORMED_PEERS as u32 + 10 {
+            server_socket.receive(SocketAddr::from((std::net::Ipv4Addr::from(0x0b00_0000 + host), 4000)), &[0; 4], now);
+        }
+        assert_eq!(server_socket.malformed.len(), MAX_MALFORMED_PEERS);
+    }
+
+    #[test]
+    fn version_mismatches_are_answered_once_per_interval() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let addr = peer.local_addr().unwrap();
+        let mut server_socket = ServerSocket::new(socket, MalformedPolicy { log: false, block_threshold: 0 }, Duration::from_secs(30), 16);
+
+        let mut login = Packet::unconnected(0, ClientMessage::Login("old".into()));
+        login.version = PROTOCOL_VERSION - 1;
+        let bytes = login.encode().unwrap();
+        let start = Instant::now();
+        for step in 0..5 {
+            assert!(server_socket.receive(addr, &bytes, start + VERSION_REPLY_INTERVAL / 4 * step).is_empty());
+        }
+        assert_eq!(server_socket.malformed[&addr.ip()].0, 5);
+
+        // the fifth packet came a whole interval after the first
+        std::thread::sleep(Duration::from_millis(100));
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut replies = 0;
+        while let Ok(len) = peer.recv(&mut buf) {
+            let packet = Packet::<ServerMessage>::decode(&buf[..len]).unwrap();
+            assert!(matches!(packet.message, ServerMessage::Rejected(RejectReason::VersionMismatch { supported: PROTOCOL_VERSION })));
+            replies += 1;
+        }
+        assert_eq!(replies, 2);
+    }
+
+    #[test]
+    fn housekeeping_waits_for_its_interval() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server_socket = ServerSocket::new(socket, MalformedPolicy { log: false, block_threshold: 0 }, HOUSEKEEPING_INTERVAL / 4, 16);
+        let start = Instant::now();
+        server_socket.resend(start);
+        assert_eq!(server_socket.next_wakeup(), start + HOUSEKEEPING_INTERVAL);
+
+        for host in 0..100 {
+            server_socket.receive(SocketAddr::from(([10, 0, 0, host], 4000)), &[0; 4], start);
+        }
+        // already idle, but only forgotten once the interval is over
+        server_socket.resend(start + HOUSEKEEPING_INTERVAL / 2);
+        assert_eq!(server_socket.malformed.len(), 100);
+        server_socket.resend(start + HOUSEKEEPING_INTERVAL);
+        assert!(server_socket.malformed.is_empty());
+
+        // a reliable message brings the wakeup forward to its resend
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let now = start + HOUSEKEEPING_INTERVAL;
+        let login = Connection::<ClientMessage, ServerMessage>::new(now).send(ClientMessage::Login("tester".into()), 0, now).unwrap();
+        assert_eq!(server_socket.receive(addr, &login, now).len(), 1);
+        server_socket.send_message(ServerMessage::PlayerLeft(0), 0, addr, now);
+        assert!(server_socket.next_wakeup() < now + HOUSEKEEPING_INTERVAL);
+    }
+
+    #[test]
+    fn sends_keep_the_configured_rate() {
+        for (tick_rate, send_rate) in [(60, 30), (60, 60), (60, 25), (60, 7), (50, 1)] {
+            let mut app = App::new();
+            app.insert_resource(ServerConfig { tick_rate, send_rate, ..default() });
+            let mut sends = Vec::new();
+            for tick in 1..=tick_rate * 10 {
+                app.insert_resource(Tick(tick));
+                if app.world_mut().run_system_cached(send_tick).unwrap() {
+                    sends.push(tick);
+                }
             }
+            assert_eq!(sends.len() as u32, send_rate * 10, "{} sends per second at {} ticks per second", send_rate, tick_rate);
+            // as evenly spread as whole ticks allow
+            let gaps: Vec<u32> = sends.windows(2).map(|pair| pair[1] - pair[0]).collect();
+            assert!(gaps.iter().max().unwrap_or(&0) - gaps.iter().min().unwrap_or(&0) <= 1);
         }
     }
 }
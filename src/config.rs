@@ -0,0 +1,444 @@
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{Areas, AREA_GAP, HEARTBEAT_INTERVAL, MAX_MESSAGE_SIZE, MAX_NAME_LENGTH, PORTAL_WIDTH};
+
+/// everything the server can be tuned with, read from a ron file and overridden on the command line
+#[derive(Resource, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+	pub bind: SocketAddr,
+	pub tick_rate: u32, // simulation steps per second
+	pub send_rate: u32, // snapshots per second, at most the tick rate
+	pub areas: Vec<AreaConfig>, // players start in the first one and move on through the portals
+	pub safe_zone_width: f32, // the safe zones span the full height at the left and right end of every area
+	pub interest_radius: f32, // enemies closer than this to a player are replicated to it
+	pub max_players: usize,
+	pub connection_timeout: f32, // seconds without any message after which a player is removed
+	pub log_malformed: bool, // print every packet that could not be decoded
+	pub malformed_block_threshold: u32, // peers sending this many malformed packets are ignored for a while, 0 never blocks
+	pub respawn: RespawnPolicy,
+	pub respawn_delay: f32, // seconds a dead player waits with the Delayed policy
+	pub mtu: usize, // encoded size budget of a single message, the packet header comes on top
+	pub position_precision: f32, // world units per quantization step of replicated positions
+}
+
+/// size and difficulty of one area of the map
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AreaConfig {
+	pub width: f32,
+	pub height: f32,
+	pub enemy_count: usize,
+	pub enemy_radius: f32,
+	pub enemy_speed_min: f32,
+	pub enemy_speed_max: f32,
+}
+
+impl Default for AreaConfig {
+	fn default() -> Self {
+		Self {
+			width: 6000.,
+			height: 1500.,
+			enemy_count: 400,
+			enemy_radius: 20.,
+			enemy_speed_min: 50.,
+			enemy_speed_max: 200.,
+		}
+	}
+}
+
+/// when a dead player that asks for it gets back into the game
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RespawnPolicy {
+	Instant,
+	Delayed, // once respawn_delay passed since the death
+	TeamWiped, // only once nobody is alive or downed anymore, everyone starts over together
+}
+
+impl FromStr for RespawnPolicy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+		    "instant" => Ok(RespawnPolicy::Instant),
+		    "delayed" => Ok(RespawnPolicy::Delayed),
+		    "team-wiped" => Ok(RespawnPolicy::TeamWiped),
+		    _ => Err(()),
+		}
+	}
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		Self {
+			bind: SocketAddr::from(([0, 0, 0, 0], 7878)),
+			tick_rate: 60,
+			send_rate: 30,
+			// every area is a bit more crowded, faster and has bigger enemies than the one before
+			areas: (0..4)
+				.map(|level| AreaConfig {
+					enemy_count: 400 + level * 200,
+					enemy_radius: 20. + level as f32 * 5.,
+					enemy_speed_min: 50. + level as f32 * 25.,
+					enemy_speed_max: 200. + level as f32 * 50.,
+					..default()
+				})
+				.collect(),
+			safe_zone_width: 300.,
+			interest_radius: 500.,
+			max_players: 32,
+			connection_timeout: 10.,
+			log_malformed: true,
+			malformed_block_threshold: 20,
+			respawn: RespawnPolicy::Delayed,
+			respawn_delay: 5.,
+			mtu: MAX_MESSAGE_SIZE,
+			position_precision: 0.1,
+		}
+	}
+}
+
+/// most areas a map can have, more would not fit into the login packet
+pub const MAX_AREAS: usize = 32;
+/// longest connection_timeout in seconds, dead clients should not be kept around for much longer
+pub const MAX_CONNECTION_TIMEOUT: f32 = 3600.;
+/// smallest message budget, a player info with the longest name still has to fit on its own
+pub const MIN_MTU: usize = 128;
+/// most quantization steps along an axis, more are neither exact in an f32 nor far from overflowing the u32 they are sent as
+pub const MAX_QUANTIZATION_STEPS: f32 = (1 << 24) as f32;
+
+/// the number of areas can only be set in the config file, the enemy flags and --half-boundary apply to every area
+/// and --area changes a single field of one area, e.g. --area 1.enemy_count=800
+pub const SERVER_USAGE: &str = "usage: server [--config <file.ron>] [--bind <addr:port>] [--tick-rate <hz>] [--send-rate <hz>]
+              [--half-boundary <units>] [--enemy-count <n>] [--enemy-radius <units>]
+              [--enemy-speed-min <units/s>] [--enemy-speed-max <units/s>] [--area <index>.<field>=<value>]
+              [--safe-zone-width <units>] [--interest-radius <units>] [--max-players <n>] [--connection-timeout <seconds>]
+              [--log-malformed <true|false>] [--malformed-block-threshold <n>]
+              [--respawn <instant|delayed|team-wiped>] [--respawn-delay <seconds>] [--mtu <bytes>]
+              [--position-precision <units>]";
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Help, // --help was passed, not really an error but stops the startup as well
+	UnknownFlag(String),
+	MissingValue(String),
+	InvalidValue { flag: String, value: String },
+	Read(PathBuf, std::io::Error),
+	Parse(PathBuf, ron::error::SpannedError),
+	Invalid(String), // the settings were read fine but do not make sense together
+	Resolve(String, String), // host and why it could not be looked up
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+		    ConfigError::Help => write!(f, "help requested"),
+		    ConfigError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+		    ConfigError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+		    ConfigError::InvalidValue { flag, value } => write!(f, "{:?} is not a valid value for {}", value, flag),
+		    ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+		    ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+		    ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+		    ConfigError::Resolve(host, reason) => write!(f, "could not resolve {}: {}", host, reason),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, ConfigError> {
+	let value = value.ok_or_else(|| ConfigError::MissingValue(flag.to_string()))?;
+	value.parse().map_err(|_| ConfigError::InvalidValue { flag: flag.to_string(), value })
+}
+
+impl ServerConfig {
+	/// reads the config file given with --config, if any, and applies the remaining flags on top of it
+	pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+		let args: Vec<String> = args.into_iter().collect();
+
+		let mut config = match args.iter().position(|arg| arg == "--config") {
+		    Some(index) => Self::from_file(parse_value::<PathBuf>("--config", args.get(index + 1).cloned())?)?,
+		    None => Self::default(),
+		};
+
+		let mut args = args.into_iter();
+		while let Some(flag) = args.next() {
+			let value = args.next();
+			match flag.as_str() {
+			    "--help" | "-h" => return Err(ConfigError::Help),
+			    "--config" => {}, // already read
+			    "--bind" => config.bind = parse_value(&flag, value)?,
+			    "--tick-rate" => config.tick_rate = parse_value(&flag, value)?,
+			    "--send-rate" => config.send_rate = parse_value(&flag, value)?,
+			    // square areas, like the single arena before the map was split
+			    "--half-boundary" => {
+			        let half_boundary: f32 = parse_value(&flag, value)?;
+			        config.areas.iter_mut().for_each(|area| (area.width, area.height) = (2. * half_boundary, 2. * half_boundary));
+			    },
+			    "--enemy-count" => {
+			        let enemy_count = parse_value(&flag, value)?;
+			        config.areas.iter_mut().for_each(|area| area.enemy_count = enemy_count);
+			    },
+			    "--enemy-radius" => {
+			        let enemy_radius = parse_value(&flag, value)?;
+			        config.areas.iter_mut().for_each(|area| area.enemy_radius = enemy_radius);
+			    },
+			    "--enemy-speed-min" => {
+			        let enemy_speed_min = parse_value(&flag, value)?;
+			        config.areas.iter_mut().for_each(|area| area.enemy_speed_min = enemy_speed_min);
+			    },
+			    "--enemy-speed-max" => {
+			        let enemy_speed_max = parse_value(&flag, value)?;
+			        config.areas.iter_mut().for_each(|area| area.enemy_speed_max = enemy_speed_max);
+			    },
+			    "--area" => config.set_area_field(&flag, value)?,
+			    "--safe-zone-width" => config.safe_zone_width = parse_value(&flag, value)?,
+			    "--interest-radius" => config.interest_radius = parse_value(&flag, value)?,
+			    "--max-players" => config.max_players = parse_value(&flag, value)?,
+			    "--connection-timeout" => config.connection_timeout = parse_value(&flag, value)?,
+			    "--log-malformed" => config.log_malformed = parse_value(&flag, value)?,
+			    "--malformed-block-threshold" => config.malformed_block_threshold = parse_value(&flag, value)?,
+			    "--respawn" => config.respawn = parse_value(&flag, value)?,
+			    "--respawn-delay" => config.respawn_delay = parse_value(&flag, value)?,
+			    "--mtu" => config.mtu = parse_value(&flag, value)?,
+			    "--position-precision" => config.position_precision = parse_value(&flag, value)?,
+			    _ => return Err(ConfigError::UnknownFlag(flag)),
+			}
+		}
+
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// fields missing in the file keep their default
+	pub fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+		let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+		ron::from_str(&text).map_err(|e| ConfigError::Parse(path, e))
+	}
+
+	/// applies a value of the form <index>.<field>=<value>, the fields are named like in the config file
+	fn set_area_field(&mut self, flag: &str, value: Option<String>) -> Result<(), ConfigError> {
+		let value = value.ok_or_else(|| ConfigError::MissingValue(flag.to_string()))?;
+		let invalid = || ConfigError::InvalidValue { flag: flag.to_string(), value: value.clone() };
+		let (index, assignment) = value.split_once('.').ok_or_else(invalid)?;
+		let (field, field_value) = assignment.split_once('=').ok_or_else(invalid)?;
+		let area = index.parse::<usize>().ok().and_then(|index| self.areas.get_mut(index)).ok_or_else(invalid)?;
+		let parsed = match field {
+		    "width" => field_value.parse().map(|width| area.width = width).is_ok(),
+		    "height" => field_value.parse().map(|height| area.height = height).is_ok(),
+		    "enemy_count" => field_value.parse().map(|enemy_count| area.enemy_count = enemy_count).is_ok(),
+		    "enemy_radius" => field_value.parse().map(|enemy_radius| area.enemy_radius = enemy_radius).is_ok(),
+		    "enemy_speed_min" => field_value.parse().map(|enemy_speed_min| area.enemy_speed_min = enemy_speed_min).is_ok(),
+		    "enemy_speed_max" => field_value.parse().map(|enemy_speed_max| area.enemy_speed_max = enemy_speed_max).is_ok(),
+		    _ => false,
+		};
+		if !parsed {
+			return Err(invalid());
+		}
+		Ok(())
+	}
+
+	#[allow(clippy::neg_cmp_op_on_partial_ord)] // the float checks are negated on purpose, see below
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+		if self.tick_rate == 0 {
+			return invalid("tick_rate must be at least 1".to_string());
+		}
+		if self.send_rate == 0 || self.send_rate > self.tick_rate {
+			return invalid(format!("send_rate must be between 1 and the tick_rate {}", self.tick_rate));
+		}
+		// the whole map is sent to the client in a single packet on login
+		if self.areas.is_empty() || self.areas.len() > MAX_AREAS {
+			return invalid(format!("there must be between 1 and {} areas", MAX_AREAS));
+		}
+		// the float checks are negated so that NaN fails them as well
+		if !(self.safe_zone_width > PORTAL_WIDTH) || !self.safe_zone_width.is_finite() {
+			return invalid(format!("safe_zone_width must be wider than the portals, which are {} wide", PORTAL_WIDTH));
+		}
+		for (index, area) in self.areas.iter().enumerate() {
+			let invalid_area = |reason: &str| invalid(format!("area {}: {}", index, reason));
+			if !(area.width > 0. && area.height > 0. && area.width.is_finite() && area.height.is_finite()) {
+				return invalid_area("width and height must be positive numbers");
+			}
+			if !(area.enemy_radius > 0.) {
+				return invalid_area("enemy_radius must be positive");
+			}
+			// enemies spawned inside a safe zone would be stuck in it
+			if !(area.width - 2. * self.safe_zone_width > 2. * area.enemy_radius && area.height > 2. * area.enemy_radius) {
+				return invalid_area("there has to be room for enemies between the safe zones");
+			}
+			if !(area.enemy_speed_min >= 0. && area.enemy_speed_min <= area.enemy_speed_max && area.enemy_speed_max.is_finite()) {
+				return invalid_area("enemy speeds must satisfy 0 <= enemy_speed_min <= enemy_speed_max");
+			}
+		}
+		if !(self.interest_radius > 0. && self.interest_radius < AREA_GAP) {
+			return invalid(format!("interest_radius must be positive and less than the gap of {} between areas", AREA_GAP));
+		}
+		if self.max_players == 0 {
+			return invalid("max_players must be at least 1".to_string());
+		}
+		// idle clients only send heartbeats, a few of them may get lost
+		if !(self.connection_timeout >= 3. * HEARTBEAT_INTERVAL && self.connection_timeout <= MAX_CONNECTION_TIMEOUT) {
+			return invalid(format!("connection_timeout must be between {} and {} seconds", 3. * HEARTBEAT_INTERVAL, MAX_CONNECTION_TIMEOUT));
+		}
+		if !(self.respawn_delay >= 0.) || !self.respawn_delay.is_finite() {
+			return invalid("respawn_delay must not be negative".to_string());
+		}
+		// bigger messages would make packets the clients refuse to decode
+		if self.mtu < MIN_MTU || self.mtu > MAX_MESSAGE_SIZE {
+			return invalid(format!("mtu must be between {} and {}", MIN_MTU, MAX_MESSAGE_SIZE));
+		}
+		if !(self.position_precision > 0. && self.position_precision.is_finite()) {
+			return invalid("position_precision must be positive".to_string());
+		}
+		let steps = self.layout().bounds().size().max_element() / self.position_precision;
+		if !(steps < MAX_QUANTIZATION_STEPS) {
+			return invalid(format!("position_precision is too fine, the map would need {} steps but at most {} are supported", steps.ceil(), MAX_QUANTIZATION_STEPS));
+		}
+		Ok(())
+	}
+
+	/// where the configured areas lie in the world
+	pub fn layout(&self) -> Areas {
+		Areas::layout(self.areas.iter().map(|area| Vec2::new(area.width, area.height)), self.safe_zone_width)
+	}
+}
+
+/// where the client connects to and who it is, read from a ron file and overridden on the command line
+#[derive(Resource, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+	pub server: String, // hostname or ip address
+	pub port: u16,
+	pub name: String, // shown to the other players
+	pub interpolation_delay: f32, // seconds other entities are rendered in the past, more hides more jitter and packet loss
+	pub max_extrapolation: f32, // seconds an entity keeps moving on its own once the snapshots run out
+	pub log_malformed: bool, // print every packet from the server that could not be decoded
+}
+
+impl Default for ClientConfig {
+	fn default() -> Self {
+		Self {
+			server: "127.0.0.1".to_string(),
+			port: 7878,
+			name: "player".to_string(),
+			interpolation_delay: 0.1,
+			max_extrapolation: 0.25,
+			log_malformed: true,
+		}
+	}
+}
+
+pub const CLIENT_USAGE: &str = "usage: client [--config <file.ron>] [--server <host>] [--port <port>] [--name <name>]
+              [--interpolation-delay <seconds>] [--max-extrapolation <seconds>]
+              [--log-malformed <true|false>]";
+
+/// longest interpolation_delay and max_extrapolation in seconds, beyond that the game is not playable anymore
+pub const MAX_INTERPOLATION_TIME: f32 = 2.;
+
+impl ClientConfig {
+	/// reads the config file given with --config, if any, and applies the remaining flags on top of it
+	pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+		let args: Vec<String> = args.into_iter().collect();
+
+		let mut config = match args.iter().position(|arg| arg == "--config") {
+		    Some(index) => Self::from_file(parse_value::<PathBuf>("--config", args.get(index + 1).cloned())?)?,
+		    None => Self::default(),
+		};
+
+		let mut args = args.into_iter();
+		while let Some(flag) = args.next() {
+			let value = args.next();
+			match flag.as_str() {
+			    "--help" | "-h" => return Err(ConfigError::Help),
+			    "--config" => {}, // already read
+			    "--server" => config.server = parse_value(&flag, value)?,
+			    "--port" => config.port = parse_value(&flag, value)?,
+			    "--name" => config.name = parse_value(&flag, value)?,
+			    "--interpolation-delay" => config.interpolation_delay = parse_value(&flag, value)?,
+			    "--max-extrapolation" => config.max_extrapolation = parse_value(&flag, value)?,
+			    "--log-malformed" => config.log_malformed = parse_value(&flag, value)?,
+			    _ => return Err(ConfigError::UnknownFlag(flag)),
+			}
+		}
+
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// fields missing in the file keep their default
+	pub fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+		let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+		ron::from_str(&text).map_err(|e| ConfigError::Parse(path, e))
+	}
+
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		if self.name.trim().is_empty() || self.name.chars().count() > MAX_NAME_LENGTH {
+			return Err(ConfigError::Invalid(format!("name must have between 1 and {} characters", MAX_NAME_LENGTH)));
+		}
+		if self.name.chars().any(char::is_control) {
+			return Err(ConfigError::Invalid("name must not contain control characters".to_string()));
+		}
+		// NaN is in no range, so it fails as well
+		for (field, value) in [("interpolation_delay", self.interpolation_delay), ("max_extrapolation", self.max_extrapolation)] {
+			if !(0.0..=MAX_INTERPOLATION_TIME).contains(&value) {
+				return Err(ConfigError::Invalid(format!("{} must be between 0 and {} seconds", field, MAX_INTERPOLATION_TIME)));
+			}
+		}
+		Ok(())
+	}
+
+	/// looks up the server, ipv4 addresses are preferred since the client socket is bound to one
+	pub fn resolve(&self) -> Result<SocketAddr, ConfigError> {
+		let addrs: Vec<SocketAddr> = (self.server.as_str(), self.port)
+			.to_socket_addrs()
+			.map_err(|e| ConfigError::Resolve(self.server.clone(), e.to_string()))?
+			.collect();
+		addrs.iter()
+			.find(|addr| addr.is_ipv4())
+			.or(addrs.first())
+			.copied()
+			.ok_or_else(|| ConfigError::Resolve(self.server.clone(), "no addresses found".to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(args: &str) -> Result<ServerConfig, ConfigError> {
+		ServerConfig::from_args(args.split_whitespace().map(String::from))
+	}
+
+	#[test]
+	fn shipped_configs_are_valid() {
+		let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+		ServerConfig::from_file(dir.join("server.ron")).unwrap().validate().unwrap();
+		ClientConfig::from_file(dir.join("client.ron")).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn area_flags_apply_in_order() {
+		let config = args("--half-boundary 2000 --enemy-count 7 --enemy-speed-max 900 --area 2.enemy_count=50 --area 0.width=5000").unwrap();
+		assert!(config.areas.iter().all(|area| area.height == 4000. && area.enemy_speed_max == 900.));
+		let counts: Vec<_> = config.areas.iter().map(|area| area.enemy_count).collect();
+		assert_eq!(counts, [7, 7, 50, 7]);
+		assert_eq!(config.areas[0].width, 5000.);
+		assert_eq!(config.areas[1].width, 4000.);
+	}
+
+	#[test]
+	fn bad_area_flags_are_rejected() {
+		for value in ["9.width=100", "0.depth=1", "0.enemy_count=-1", "0.width", "width=100", "x.width=100"] {
+			assert!(matches!(args(&format!("--area {}", value)), Err(ConfigError::InvalidValue { .. })), "{} was accepted", value);
+		}
+		assert!(matches!(args("--area"), Err(ConfigError::MissingValue(_))));
+		// the values are only checked together once every flag is applied
+		assert!(matches!(args("--enemy-speed-min 300 --enemy-speed-max 100"), Err(ConfigError::Invalid(_))));
+	}
+}
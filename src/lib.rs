@@ -1,13 +1,49 @@
 use bincode::{Decode, Encode};
 pub use rand::Rng;
-pub use bevy::window::PrimaryWindow;
+pub use bevy::window::{PrimaryWindow, WindowCloseRequested};
+pub use bevy::prelude::*;
+#[cfg(feature = "graphics")]
 pub use bevy::{
     core_pipeline::tonemapping::{DebandDither, Tonemapping},
     post_process::bloom::{Bloom, BloomCompositeMode},
-    prelude::*,
 };
 
-pub type NetIDType = u128;
+mod area;
+mod config;
+mod reliable;
+mod simulation;
+mod spatial;
+pub use area::*;
+pub use config::*;
+pub use reliable::*;
+pub use simulation::*;
+pub use spatial::*;
+
+pub type NetIDType = u32; // varint encoded on the wire, so small ids only take a byte or two
+pub type SessionToken = u64;
+
+/// biggest datagram either side sends or expects to receive
+pub const MAX_PACKET_SIZE: usize = 1000;
+
+/// how many enemy snapshots both sides keep around as possible delta baselines
+pub const SNAPSHOT_HISTORY: usize = 32;
+
+/// seconds between two heartbeats of an otherwise idle client
+pub const HEARTBEAT_INTERVAL: f32 = 1.0;
+
+/// longest display name in characters, longer ones are cut by the server
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// names come from untrusted clients, so they are trimmed, stripped of control characters and shortened
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name.trim().chars().filter(|c| !c.is_control()).take(MAX_NAME_LENGTH).collect();
+    if name.is_empty() { "player".to_string() } else { name }
+}
+
+/// unguessable random value, used for login challenges and session tokens
+pub fn random_token() -> u64 {
+    rand::rng().random()
+}
 
 #[derive(Resource)]
 pub struct CursorPos(pub Vec2);
@@ -21,19 +57,41 @@ pub struct Radius(pub f32);
 #[derive(Component)]
 pub struct Player;
 
-#[derive(Component)]
-pub struct Alive(pub bool);
+/// only alive players move, downed ones wait for a teammate to touch them before their time runs out
+#[derive(Component, Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum PlayerState {
+	Alive,
+	Downed(f32), // seconds left until the player is dead for good
+	Dead(f32), // seconds the body stays visible before the player only spectates
+	Spectating, // out of the game, not shown and not touched by anything
+}
+
+/// how a player did since it last spawned, sent to it when it dies
+#[derive(Component, Encode, Decode, Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayerStats {
+	pub survival_time: f32, // seconds the player was alive or downed
+	pub downs: u32,
+	pub revives: u32, // teammates this player brought back
+}
 
 #[derive(Component)]
 pub struct Enemy;
 
-pub fn random_velocity() -> Vec2 {
+pub fn random_velocity(speed: std::ops::RangeInclusive<f32>) -> Vec2 {
     let mut rng = rand::rng();
     let angle = rng.random_range(0.0..std::f32::consts::TAU);
-    let speed = rng.random_range(50.0..200.0);
+    let speed = rng.random_range(speed);
     Vec2::from_angle(angle) * speed
 }
 
+pub fn random_position_in(rect: Rect) -> Vec2 {
+    let mut rng = rand::rng();
+    Vec2::new(
+        rng.random_range(rect.min.x..rect.max.x),
+        rng.random_range(rect.min.y..rect.max.y),
+    )
+}
+
 pub fn random_position(range: f32) -> Vec2 {
     let mut rng = rand::rng();
     Vec2::new(
@@ -42,25 +100,25 @@ pub fn random_position(range: f32) -> Vec2 {
     )
 }
 
-#[derive(Encode, Decode, Debug, Clone, Copy)]
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
 pub struct MyVec3 {
 	x: f32,
 	y: f32,
 	z: f32,
 }
 
-impl Into<Vec3> for MyVec3 {
-    fn into(self) -> Vec3 {
-    	Vec3::new(self.x, self.y, self.z)
+impl From<MyVec3> for Vec3 {
+    fn from(vec: MyVec3) -> Self {
+    	Vec3::new(vec.x, vec.y, vec.z)
     }
 }
 
-impl Into<MyVec3> for Vec3 {
-    fn into(self) -> MyVec3 {
+impl From<Vec3> for MyVec3 {
+    fn from(vec: Vec3) -> Self {
     	MyVec3 {
-	        x: self.x,
-	        y: self.y,
-	        z: self.z,
+	        x: vec.x,
+	        y: vec.y,
+	        z: vec.z,
 	    }
     }
 }
@@ -71,73 +129,536 @@ pub struct MyVec2 {
 	y: f32,
 }
 
-impl Into<Vec2> for MyVec2 {
-    fn into(self) -> Vec2 {
-    	Vec2::new(self.x, self.y)
+impl From<MyVec2> for Vec2 {
+    fn from(vec: MyVec2) -> Self {
+    	Vec2::new(vec.x, vec.y)
     }
 }
 
-impl Into<MyVec2> for Vec2 {
-    fn into(self) -> MyVec2 {
+impl From<Vec2> for MyVec2 {
+    fn from(vec: Vec2) -> Self {
     	MyVec2 {
-	        x: self.x,
-	        y: self.y,
+	        x: vec.x,
+	        y: vec.y,
 	    }
     }
 }
 
+/// a position as multiples of the quantization precision, measured from the lower arena corner
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct QuantizedVec2 {
+	pub x: u32,
+	pub y: u32,
+}
+
+/// maps positions inside the arena to integers so they take less space on the wire
+#[derive(Resource, Encode, Decode, Debug, Clone, Copy)]
+pub struct Quantization {
+	pub min: MyVec2,
+	pub max: MyVec2,
+	pub precision: f32, // positions are off by at most half of this on each axis
+}
+
+impl Quantization {
+	pub fn new(min: Vec2, max: Vec2, precision: f32) -> Self {
+		Self {
+			min: min.into(),
+			max: max.into(),
+			precision,
+		}
+	}
+	/// positions outside the bounds are clamped to them
+	pub fn quantize(&self, position: Vec2) -> QuantizedVec2 {
+		let min: Vec2 = self.min.into();
+		let max: Vec2 = self.max.into();
+		let steps = ((position.clamp(min, max) - min) / self.precision).round();
+		QuantizedVec2 {
+			x: steps.x as u32,
+			y: steps.y as u32,
+		}
+	}
+	pub fn dequantize(&self, position: QuantizedVec2) -> Vec2 {
+		let min: Vec2 = self.min.into();
+		min + Vec2::new(position.x as f32, position.y as f32) * self.precision
+	}
+}
+
+/// what the client needs to know about the server to make sense of its messages
 #[derive(Encode, Decode, Debug, Clone)]
+pub struct ServerInfo {
+	pub quantization: Quantization,
+	pub tick_rate: u32, // simulation ticks per second, packets are stamped with the tick
+	pub areas: Vec<Area>, // the map, so the client can draw it
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
 pub struct EnemyPackage {
 	pub net_id: NetIDType,
-	pub position: MyVec3,
+	pub position: QuantizedVec2,
 	pub radius: f32,
 }
 
+/// one frame of movement of the controlled player
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct PlayerInput {
+	pub sequence: u32,
+	pub dt: f32, // the duration of the client frame, the server moves the player by exactly this much
+	pub velocity: MyVec2,
+}
+
+/// longest frame a single input may cover, so clients cannot teleport by claiming huge frames
+pub const MAX_INPUT_DT: f32 = 0.1;
+
+impl PlayerInput {
+	/// used by the server and by the client prediction so both end up at the same position
+	pub fn apply(&self, translation: &mut Vec3) {
+		let velocity: Vec2 = self.velocity.into();
+		*translation += velocity.extend(0.) * self.dt.clamp(0., MAX_INPUT_DT);
+	}
+}
+
+/// an enemy relative to a baseline snapshot, fields that did not change are left out
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct EnemyDelta {
+	pub net_id: NetIDType,
+	pub position: Option<QuantizedVec2>,
+	pub radius: Option<f32>,
+}
+
+impl EnemyDelta {
+	/// without a baseline every field is sent
+	pub fn diff(baseline: Option<&EnemyPackage>, current: &EnemyPackage) -> Self {
+		Self {
+			net_id: current.net_id,
+			position: match baseline {
+			    Some(baseline) if baseline.position == current.position => None,
+			    _ => Some(current.position),
+			},
+			radius: match baseline {
+			    Some(baseline) if baseline.radius == current.radius => None,
+			    _ => Some(current.radius),
+			},
+		}
+	}
+	/// the full enemy, none if a left out field is missing in the baseline as well
+	pub fn apply(&self, baseline: Option<&EnemyPackage>) -> Option<EnemyPackage> {
+		Some(EnemyPackage {
+			net_id: self.net_id,
+			position: self.position.or(baseline.map(|baseline| baseline.position))?,
+			radius: self.radius.or(baseline.map(|baseline| baseline.radius))?,
+		})
+	}
+}
+
+/// one datagram of the enemies a client can see in a snapshot
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct EnemySnapshot {
+	pub snapshot: u32,
+	pub baseline: Option<u32>, // the acked snapshot the deltas are relative to, none for a full snapshot
+	pub fragment: u8,
+	pub fragment_count: u8, // the snapshot is complete once every fragment arrived
+	pub enemies: Vec<EnemyDelta>,
+}
+
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct PlayerPackage {
 	pub net_id: NetIDType,
-	pub position: MyVec3,
+	pub position: QuantizedVec2,
+	pub state: PlayerState,
+}
+
+/// who a player is, sent once when the player joins and not with every update
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct PlayerInfo {
+	pub net_id: NetIDType,
+	pub name: String,
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+	TooLarge, // the message does not fit into the given buffer
+	Bincode(bincode::error::EncodeError),
+}
+
+impl std::fmt::Display for EncodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+		    EncodeError::TooLarge => write!(f, "message does not fit into a packet"),
+		    EncodeError::Bincode(e) => write!(f, "could not encode message: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for EncodeError {}
+
+fn encode_into<T: Encode>(message: &T, buf: &mut [u8]) -> Result<usize, EncodeError> {
+	match bincode::encode_into_slice(message, buf, bincode::config::standard()) {
+	    Ok(len) => Ok(len),
+	    Err(bincode::error::EncodeError::UnexpectedEnd) => Err(EncodeError::TooLarge),
+	    Err(e) => Err(EncodeError::Bincode(e)),
+	}
+}
+
+pub(crate) fn encode_to_vec<T: Encode>(message: &T) -> Result<Vec<u8>, EncodeError> {
+	let mut buf = [0u8; MAX_PACKET_SIZE];
+	let len = encode_into(message, &mut buf)?;
+	Ok(buf[..len].to_vec())
+}
+
+/// why received bytes could not be turned into a message
+#[derive(Debug)]
+pub enum DecodeError {
+	TooLarge(usize), // more bytes than any valid packet has
+	TrailingBytes(usize), // the message ended before the datagram did
+	BadMagic, // not a packet of this game
+	VersionMismatch(u16), // the peer speaks the given protocol version
+	Bincode(bincode::error::DecodeError),
+}
+
+impl std::fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+		    DecodeError::TooLarge(len) => write!(f, "{} bytes are more than a packet can have", len),
+		    DecodeError::TrailingBytes(len) => write!(f, "{} bytes left after the message", len),
+		    DecodeError::BadMagic => write!(f, "packet does not start with the protocol magic"),
+		    DecodeError::VersionMismatch(version) => write!(f, "peer uses protocol version {} but we use {}", version, PROTOCOL_VERSION),
+		    DecodeError::Bincode(e) => write!(f, "could not decode message: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for DecodeError {}
+
+/// most memory a decoded packet may claim, a forged length prefix fails instead of allocating gigabytes
+const MAX_DECODE_ALLOCATION: usize = 64 * 1024;
+
+/// the whole slice has to be exactly one value
+pub(crate) fn decode_from<T: Decode<()>>(slice: &[u8]) -> Result<T, DecodeError> {
+	if slice.len() > MAX_PACKET_SIZE {
+		return Err(DecodeError::TooLarge(slice.len()));
+	}
+	let config = bincode::config::standard().with_limit::<MAX_DECODE_ALLOCATION>();
+	let (value, len) = bincode::decode_from_slice(slice, config).map_err(DecodeError::Bincode)?;
+	if len != slice.len() {
+		return Err(DecodeError::TrailingBytes(slice.len() - len));
+	}
+	Ok(value)
+}
+
+/// number of bytes the value takes on the wire
+pub fn encoded_size<T: Encode>(value: &T) -> usize {
+	let mut writer = bincode::enc::write::SizeWriter::default();
+	match bincode::encode_into_writer(value, &mut writer, bincode::config::standard()) {
+	    Ok(()) => writer.bytes_written,
+	    Err(_) => usize::MAX,
+	}
+}
+
+/// greedily packs records into messages until the encoded size of the next one would exceed the mtu
+pub struct PacketBuilder<T: Encode, F: Fn(Vec<T>) -> ServerMessage> {
+	mtu: usize,
+	wrap: F,
+	empty_size: usize, // size of the message without any records and without the length prefix
+	size: usize, // size of the records in the current message
+	records: Vec<T>,
+	messages: Vec<ServerMessage>,
+}
+
+impl<T: Encode, F: Fn(Vec<T>) -> ServerMessage> PacketBuilder<T, F> {
+	pub fn new(mtu: usize, wrap: F) -> Self {
+		let empty_size = encoded_size(&wrap(Vec::new())) - encoded_size(&0u64);
+		Self {
+			mtu,
+			wrap,
+			empty_size,
+			size: 0,
+			records: Vec::new(),
+			messages: Vec::new(),
+		}
+	}
+	fn message_size(&self, record_count: usize, records_size: usize) -> usize {
+		// vec lengths are varint encoded so the prefix grows with the count
+		self.empty_size + encoded_size(&(record_count as u64)) + records_size
+	}
+	pub fn push(&mut self, record: T) {
+		let record_size = encoded_size(&record);
+		// a record that is too large on its own still gets its own message so the error shows up when encoding
+		if !self.records.is_empty() && self.message_size(self.records.len() + 1, self.size + record_size) > self.mtu {
+			self.flush();
+		}
+		self.size += record_size;
+		self.records.push(record);
+	}
+	fn flush(&mut self) {
+		let records = std::mem::take(&mut self.records);
+		self.size = 0;
+		self.messages.push((self.wrap)(records));
+	}
+	pub fn finish(mut self) -> Vec<ServerMessage> {
+		if !self.records.is_empty() {
+			self.flush();
+		}
+		self.messages
+	}
+}
+
+impl<T: Encode, F: Fn(Vec<T>) -> ServerMessage> Extend<T> for PacketBuilder<T, F> {
+	fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+		for record in iter {
+			self.push(record);
+		}
+	}
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum ServerMessage {
-	Ok(NetIDType), // the id of the player so that it knows which id it is
-	UpdateEnemies(Vec<EnemyPackage>),
+	Challenge(u64), // has to be echoed back so the server knows the address is not spoofed
+	Ok(NetIDType, SessionToken, ServerInfo), // the id of the player so that it knows which id it is, the token to authenticate with and how the server works
+	EnemiesEntered(Vec<EnemyPackage>), // enemies that just came into the interest area of the client
+	EntitiesLeft(Vec<NetIDType>), // entities that left the interest area and should be despawned
+	UpdateEnemies(EnemySnapshot),
 	UpdatePlayers(Vec<PlayerPackage>),
+	PlayersJoined(Vec<PlayerInfo>), // names of players that joined, on login the client gets everyone including itself
+	PlayerLeft(NetIDType), // the player disconnected or timed out and should be removed
+	InputAck(u32, QuantizedVec2), // last input the server applied to the controlled player and the resulting position
+	Rejected(RejectReason), // the login was refused, the client should give up
+	GameOver(PlayerStats), // the player died for good, whether it may respawn depends on the server policy
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub enum RejectReason {
+	ServerFull,
+	VersionMismatch { supported: u16 },
+}
+
+impl std::fmt::Display for RejectReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+		    RejectReason::ServerFull => write!(f, "the server is full"),
+		    RejectReason::VersionMismatch { supported } => write!(f, "the server only supports protocol version {} but this client uses {}", supported, PROTOCOL_VERSION),
+		}
+	}
 }
 
 impl ServerMessage {
-	pub fn encode(&self) -> [u8; 1000] {
-		let mut slice = [0u8; 1000];
-		bincode::encode_into_slice(self, &mut slice, bincode::config::standard()).unwrap();
-		slice
+	/// answers to peers that did not prove they own their address, sent once and outside of any connection
+	pub fn unconnected(&self) -> bool {
+		matches!(self, ServerMessage::Challenge(_) | ServerMessage::Rejected(_))
 	}
-	pub fn decode(slice: &[u8]) -> Option<Self> {
-		let o = bincode::decode_from_slice(slice, bincode::config::standard());
-		match o {
-		    Ok(r) => Some(r.0),
-		    Err(_) => None,
-		}
+	/// only the used bytes, fails if the message is bigger than MAX_PACKET_SIZE
+	pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+		encode_to_vec(self)
+	}
+	/// writes into the given buffer and returns the used length
+	pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+		encode_into(self, buf)
+	}
+	pub fn decode(slice: &[u8]) -> Result<Self, DecodeError> {
+		decode_from(slice)
 	}
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, Clone)]
 pub enum ClientMessage {
-	Login,
-	SetVelocity(NetIDType, MyVec2),
+	Login(String), // the display name
+	ChallengeResponse(u64),
+	SetVelocity(SessionToken, PlayerInput),
+	AckSnapshot(SessionToken, u32), // every fragment of the snapshot arrived so it can be used as a baseline
+	Heartbeat(SessionToken),
+	Disconnect(SessionToken),
+	Respawn(SessionToken), // asks to play again after dying, ignored while the respawn policy does not allow it yet
+}
+
+impl Channel for ServerMessage {
+	fn reliable(&self) -> bool {
+		!matches!(self, ServerMessage::UpdateEnemies(_) | ServerMessage::UpdatePlayers(_) | ServerMessage::InputAck(..))
+	}
+}
+
+impl Channel for ClientMessage {
+	fn reliable(&self) -> bool {
+		!matches!(self, ClientMessage::SetVelocity(..) | ClientMessage::AckSnapshot(..) | ClientMessage::Heartbeat(_))
+	}
 }
 
 impl ClientMessage {
-	pub fn encode(&self) -> [u8; 1000] {
-		let mut slice = [0u8; 1000];
-		bincode::encode_into_slice(self, &mut slice, bincode::config::standard()).unwrap();
-		slice
-	}
-	pub fn decode(slice: &[u8]) -> Option<Self> {
-		let o = bincode::decode_from_slice(slice, bincode::config::standard());
-		match o {
-		    Ok(r) => Some(r.0),
-		    Err(_) => None,
+	/// the session token of messages that are only valid after the handshake
+	pub fn token(&self) -> Option<SessionToken> {
+		match self {
+		    ClientMessage::Login(_) | ClientMessage::ChallengeResponse(_) => None,
+		    ClientMessage::SetVelocity(token, _)
+		    | ClientMessage::AckSnapshot(token, _)
+		    | ClientMessage::Heartbeat(token)
+		    | ClientMessage::Disconnect(token)
+		    | ClientMessage::Respawn(token) => Some(*token),
+		}
+	}
+	/// only the used bytes, fails if the message is bigger than MAX_PACKET_SIZE
+	pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+		encode_to_vec(self)
+	}
+	/// writes into the given buffer and returns the used length
+	pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+		encode_into(self, buf)
+	}
+	pub fn decode(slice: &[u8]) -> Result<Self, DecodeError> {
+		decode_from(slice)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::{Rng, SeedableRng, rngs::StdRng};
+
+	use super::*;
+
+	/// the message in a packet with the largest header a connection can produce
+	fn packet_size(message: &ServerMessage) -> usize {
+		encoded_size(&Packet {
+			magic: PROTOCOL_MAGIC,
+			version: PROTOCOL_VERSION,
+			tick: u32::MAX,
+			ack: Some(u16::MAX),
+			ack_bits: u32::MAX,
+			reliable_id: Some(u16::MAX),
+			message,
+		})
+	}
+
+	/// every message fits into a packet and into the mtu, and no message could have taken the next record as well
+	fn assert_packed<T: Encode + Clone, F: Fn(Vec<T>) -> ServerMessage>(mtu: usize, wrap: F, records: Vec<T>, unwrap: impl Fn(&ServerMessage) -> Vec<T>) {
+		let mut builder = PacketBuilder::new(mtu, &wrap);
+		builder.extend(records.iter().cloned());
+		let messages = builder.finish();
+
+		let packed: Vec<Vec<T>> = messages.iter().map(&unwrap).collect();
+		assert_eq!(packed.iter().map(Vec::len).sum::<usize>(), records.len());
+		for (index, message) in messages.iter().enumerate() {
+			assert!(encoded_size(message) <= mtu, "message of {} bytes exceeds the mtu of {}", encoded_size(message), mtu);
+			assert!(packet_size(message) <= MAX_PACKET_SIZE);
+			if let Some(next) = packed.get(index + 1) {
+				let mut grown = packed[index].clone();
+				grown.push(next[0].clone());
+				assert!(encoded_size(&wrap(grown)) > mtu, "message {} could have taken another record", index);
+			}
+		}
+	}
+
+	/// spread over every varint length, so the records differ in size
+	fn random_varint(rng: &mut StdRng) -> u32 {
+		let bits = rng.random_range(1..32);
+		rng.random_range(0..1 << bits)
+	}
+
+	fn random_position(rng: &mut StdRng) -> QuantizedVec2 {
+		QuantizedVec2 { x: random_varint(rng), y: random_varint(rng) }
+	}
+
+	fn enemy_snapshot(enemies: Vec<EnemyDelta>) -> ServerMessage {
+		ServerMessage::UpdateEnemies(EnemySnapshot { snapshot: u32::MAX, baseline: Some(u32::MAX), fragment: u8::MAX, fragment_count: u8::MAX, enemies })
+	}
+
+	/// the delta as the client decodes it from a snapshot
+	fn sent(delta: EnemyDelta) -> EnemyDelta {
+		let bytes = enemy_snapshot(vec![delta]).encode().unwrap();
+		match ServerMessage::decode(&bytes).unwrap() {
+		    ServerMessage::UpdateEnemies(mut snapshot) => snapshot.enemies.remove(0),
+		    _ => unreachable!(),
+		}
+	}
+
+	#[test]
+	fn enemy_deltas_round_trip() {
+		let baseline = EnemyPackage { net_id: 7, position: QuantizedVec2 { x: 1000, y: 20 }, radius: 20. };
+		let moved = EnemyPackage { position: QuantizedVec2 { x: 1003, y: 20 }, ..baseline.clone() };
+
+		// against a baseline only what changed is sent
+		let delta = sent(EnemyDelta::diff(Some(&baseline), &moved));
+		assert_eq!((delta.position, delta.radius), (Some(moved.position), None));
+		assert_eq!(delta.apply(Some(&baseline)), Some(moved.clone()));
+		let unchanged = sent(EnemyDelta::diff(Some(&baseline), &baseline));
+		assert_eq!((unchanged.position, unchanged.radius), (None, None));
+		assert_eq!(unchanged.apply(Some(&baseline)), Some(baseline.clone()));
+
+		// a full snapshot needs no baseline
+		let full = sent(EnemyDelta::diff(None, &moved));
+		assert_eq!((full.position, full.radius), (Some(moved.position), Some(moved.radius)));
+		assert_eq!(full.apply(None), Some(moved.clone()));
+
+		// the enemy is not in the baseline the client has, so the left out radius is unknown
+		assert_eq!(delta.apply(None), None);
+		assert_eq!(unchanged.apply(None), None);
+	}
+
+	#[test]
+	fn quantization_round_trips_within_half_a_step() {
+		let mut rng = StdRng::seed_from_u64(4);
+		for precision in [0.01, 0.1, 1., 7.3] {
+			let (min, max) = (Vec2::new(-100., -750.), Vec2::new(30_000., 750.));
+			let quantization = Quantization::new(min, max, precision);
+			for _ in 0..10_000 {
+				let position = Vec2::new(rng.random_range(min.x..=max.x), rng.random_range(min.y..=max.y));
+				let error = (quantization.dequantize(quantization.quantize(position)) - position).abs();
+				// f32 itself is off by a few ulps at this distance from the origin
+				let tolerance = precision / 2. + position.abs().max_element() * f32::EPSILON * 4.;
+				assert!(error.max_element() <= tolerance, "{} came back {} off with precision {}", position, error, precision);
+			}
+		}
+	}
+
+	#[test]
+	fn quantization_clamps_to_the_bounds() {
+		let (min, max) = (Vec2::new(-100., -50.), Vec2::new(100., 50.));
+		let quantization = Quantization::new(min, max, 0.1);
+		let round_trip = |position: Vec2| quantization.dequantize(quantization.quantize(position));
+		assert!((round_trip(Vec2::new(-1000., 0.)) - Vec2::new(-100., 0.)).length() < 0.01);
+		assert!((round_trip(Vec2::new(1000., 1000.)) - max).length() < 0.01);
+		assert!((round_trip(Vec2::new(-1e30, -1e30)) - min).length() < 0.01);
+		assert!((round_trip(Vec2::new(50., -60.)) - Vec2::new(50., -50.)).length() < 0.01);
+	}
+
+	#[test]
+	fn packet_builder_respects_the_mtu() {
+		let mut rng = StdRng::seed_from_u64(3);
+		for count in [0, 1, 50, 127, 128, 129, 1000] {
+			let deltas: Vec<_> = (0..count)
+				.map(|_| EnemyDelta {
+					net_id: rng.random(),
+					position: rng.random_bool(0.7).then(|| random_position(&mut rng)),
+					radius: rng.random_bool(0.2).then(|| rng.random()),
+				})
+				.collect();
+			assert_packed(MAX_MESSAGE_SIZE, enemy_snapshot, deltas, |message| match message {
+			    ServerMessage::UpdateEnemies(snapshot) => snapshot.enemies.clone(),
+			    _ => unreachable!(),
+			});
+
+			let players: Vec<_> = (0..count)
+				.map(|_| PlayerPackage { net_id: rng.random(), position: random_position(&mut rng), state: PlayerState::Downed(rng.random()) })
+				.collect();
+			assert_packed(MAX_MESSAGE_SIZE, ServerMessage::UpdatePlayers, players, |message| match message {
+			    ServerMessage::UpdatePlayers(players) => players.clone(),
+			    _ => unreachable!(),
+			});
+
+			let net_ids: Vec<NetIDType> = (0..count).map(|_| random_varint(&mut rng)).collect();
+			assert_packed(MAX_MESSAGE_SIZE, ServerMessage::EntitiesLeft, net_ids, |message| match message {
+			    ServerMessage::EntitiesLeft(net_ids) => net_ids.clone(),
+			    _ => unreachable!(),
+			});
+		}
+	}
+
+	#[test]
+	fn packet_builder_counts_the_length_prefix() {
+		// one byte ids, so the mtus around 128 records decide whether the prefix grows from one to three bytes
+		let net_ids: Vec<NetIDType> = vec![1; 300];
+		let empty = encoded_size(&ServerMessage::EntitiesLeft(Vec::new()));
+		for mtu in empty + 120..empty + 140 {
+			assert_packed(mtu, ServerMessage::EntitiesLeft, net_ids.clone(), |message| match message {
+			    ServerMessage::EntitiesLeft(net_ids) => net_ids.clone(),
+			    _ => unreachable!(),
+			});
 		}
 	}
 }
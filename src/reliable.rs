@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use bincode::{Decode, Encode};
+
+use crate::{decode_from, encode_to_vec, DecodeError, EncodeError, MAX_PACKET_SIZE};
+
+/// every packet starts with these, datagrams of other programs are told apart right away
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"DSC2";
+/// has to be raised whenever the encoding of any message changes
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// how long a reliable message waits for its ack before it is sent again
+pub const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+/// a message that was sent this often without an ack is given up on together with its connection
+pub const MAX_RESENDS: u32 = 50;
+/// reliable messages further than this ahead of the next one in order are dropped unacked, the sender repeats them later
+pub const REORDER_WINDOW: u16 = 64;
+/// upper bound of the bytes a packet adds around its message
+pub const MAX_HEADER_SIZE: usize = 28;
+/// the encoded size budget left for a single message
+pub const MAX_MESSAGE_SIZE: usize = MAX_PACKET_SIZE - MAX_HEADER_SIZE;
+
+/// everything that goes over the socket is wrapped into one of these
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Packet<M> {
+	pub magic: [u8; 4],
+	pub version: u16, // PROTOCOL_VERSION of the sender, stays at the front so every version can read it
+	pub tick: u32, // server tick the message belongs to, clients send the newest tick they know
+	pub ack: Option<u16>, // newest reliable id received from the peer
+	pub ack_bits: u32, // bit n set means ack - 1 - n was received as well
+	pub reliable_id: Option<u16>, // none for messages on the unreliable channel
+	pub message: M,
+}
+
+/// the part of the header every protocol version agrees on
+#[derive(Decode)]
+struct PacketPrefix {
+	magic: [u8; 4],
+	version: u16,
+}
+
+impl<M: Encode> Packet<M> {
+	/// a packet outside of any connection, it carries no acks and is never resent
+	pub fn unconnected(tick: u32, message: M) -> Self {
+		Self {
+			magic: PROTOCOL_MAGIC,
+			version: PROTOCOL_VERSION,
+			tick,
+			ack: None,
+			ack_bits: 0,
+			reliable_id: None,
+			message,
+		}
+	}
+	pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+		encode_to_vec(self)
+	}
+}
+
+impl<M: Decode<()>> Packet<M> {
+	/// the prefix is checked first so packets of another version are not misread as garbage
+	pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+		let (prefix, _) = bincode::decode_from_slice::<PacketPrefix, _>(bytes, bincode::config::standard())
+			.map_err(DecodeError::Bincode)?;
+		if prefix.magic != PROTOCOL_MAGIC {
+			return Err(DecodeError::BadMagic);
+		}
+		if prefix.version != PROTOCOL_VERSION {
+			return Err(DecodeError::VersionMismatch(prefix.version));
+		}
+		decode_from(bytes)
+	}
+}
+
+/// messages that decide themselves on which channel they travel
+pub trait Channel {
+	fn reliable(&self) -> bool;
+}
+
+/// a is newer than b, taking wrap around into account
+fn sequence_greater(a: u16, b: u16) -> bool {
+	a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+/// reliable ordered and unreliable channel to a single peer, S is sent and R received
+pub struct Connection<S, R> {
+	next_reliable_id: u16,
+	unacked: VecDeque<(u16, u32, S, Instant, u32)>, // reliable messages with their tick, last send time and resend count that have no ack yet
+	ack: Option<u16>,
+	ack_bits: u32,
+	next_delivery: u16, // reliable id the peer sends next in order
+	out_of_order: HashMap<u16, (u32, R)>, // reliable messages that arrived before their predecessors
+	pub last_received: Instant,
+}
+
+impl<S, R> Connection<S, R>
+where
+	S: Encode + Channel + Clone,
+	R: Decode<()>,
+{
+	pub fn new(now: Instant) -> Self {
+		Self {
+			next_reliable_id: 0,
+			unacked: VecDeque::new(),
+			ack: None,
+			ack_bits: 0,
+			next_delivery: 0,
+			out_of_order: HashMap::new(),
+			last_received: now,
+		}
+	}
+
+	fn packet(&self, tick: u32, reliable_id: Option<u16>, message: &S) -> Result<Vec<u8>, EncodeError> {
+		encode_to_vec(&Packet {
+			magic: PROTOCOL_MAGIC,
+			version: PROTOCOL_VERSION,
+			tick,
+			ack: self.ack,
+			ack_bits: self.ack_bits,
+			reliable_id,
+			message,
+		})
+	}
+
+	/// wraps the message into a packet, reliable messages are kept until the peer acks them
+	pub fn send(&mut self, message: S, tick: u32, now: Instant) -> Result<Vec<u8>, EncodeError> {
+		if !message.reliable() {
+			return self.packet(tick, None, &message);
+		}
+		let reliable_id = self.next_reliable_id;
+		let bytes = self.packet(tick, Some(reliable_id), &message)?;
+		self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+		self.unacked.push_back((reliable_id, tick, message, now, 0));
+		Ok(bytes)
+	}
+
+	/// packets for every reliable message whose ack is overdue
+	pub fn resend(&mut self, now: Instant) -> Vec<Vec<u8>> {
+		let mut packets = Vec::new();
+		for index in 0..self.unacked.len() {
+			let (reliable_id, tick, message, last_sent, resends) = &self.unacked[index];
+			if *resends >= MAX_RESENDS || now.duration_since(*last_sent) < RESEND_INTERVAL {
+				continue;
+			}
+			if let Ok(bytes) = self.packet(*tick, Some(*reliable_id), message) {
+				packets.push(bytes);
+			}
+			self.unacked[index].3 = now;
+			self.unacked[index].4 += 1;
+		}
+		packets
+	}
+
+	/// when the oldest unacked reliable message is due again, none if everything was acked or given up on
+	pub fn next_resend(&self) -> Option<Instant> {
+		self.unacked
+			.iter()
+			.filter(|(_, _, _, _, resends)| *resends < MAX_RESENDS)
+			.map(|(_, _, _, last_sent, _)| *last_sent + RESEND_INTERVAL)
+			.min()
+	}
+
+	/// a reliable message ran out of resends, the peer is gone or never was there
+	pub fn gave_up(&self) -> bool {
+		self.unacked.iter().any(|(_, _, _, _, resends)| *resends >= MAX_RESENDS)
+	}
+
+	/// decodes a packet and returns the messages that are ready with their tick, reliable ones in the order they were sent
+	pub fn receive(&mut self, bytes: &[u8], now: Instant) -> Result<Vec<(u32, R)>, DecodeError> {
+		Ok(self.receive_packet(Packet::decode(bytes)?, now))
+	}
+
+	/// same as receive for a packet that was already decoded
+	pub fn receive_packet(&mut self, packet: Packet<R>, now: Instant) -> Vec<(u32, R)> {
+		self.last_received = now;
+		self.process_acks(packet.ack, packet.ack_bits);
+
+		let Some(reliable_id) = packet.reliable_id else {
+			return vec![(packet.tick, packet.message)];
+		};
+		// a peer cannot make us buffer an unbounded number of messages by skipping ids
+		if reliable_id.wrapping_sub(self.next_delivery) >= REORDER_WINDOW && sequence_greater(reliable_id, self.next_delivery) {
+			return Vec::new();
+		}
+		self.record_ack(reliable_id);
+
+		if reliable_id != self.next_delivery {
+			// duplicates of delivered messages are dropped, newer ones wait for the gap to be filled
+			if sequence_greater(reliable_id, self.next_delivery) {
+				self.out_of_order.insert(reliable_id, (packet.tick, packet.message));
+			}
+			return Vec::new();
+		}
+
+		let mut messages = vec![(packet.tick, packet.message)];
+		self.next_delivery = self.next_delivery.wrapping_add(1);
+		while let Some(message) = self.out_of_order.remove(&self.next_delivery) {
+			messages.push(message);
+			self.next_delivery = self.next_delivery.wrapping_add(1);
+		}
+		messages
+	}
+
+	fn record_ack(&mut self, reliable_id: u16) {
+		let Some(ack) = self.ack else {
+			self.ack = Some(reliable_id);
+			return;
+		};
+		if sequence_greater(reliable_id, ack) {
+			let shift = reliable_id.wrapping_sub(ack) as u32;
+			self.ack_bits = if shift > 32 { 0 } else { (self.ack_bits << (shift - 1) << 1) | (1 << (shift - 1)) };
+			self.ack = Some(reliable_id);
+		} else {
+			let distance = ack.wrapping_sub(reliable_id) as u32;
+			if (1..=32).contains(&distance) {
+				self.ack_bits |= 1 << (distance - 1);
+			}
+		}
+	}
+
+	fn process_acks(&mut self, ack: Option<u16>, ack_bits: u32) {
+		let Some(ack) = ack else {
+			return;
+		};
+		self.unacked.retain(|(reliable_id, _, _, _, _)| {
+			let distance = ack.wrapping_sub(*reliable_id) as u32;
+			let acked = distance == 0 || ((1..=32).contains(&distance) && ack_bits & (1 << (distance - 1)) != 0);
+			!acked
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::{Rng, SeedableRng, rngs::StdRng};
+
+	use super::*;
+	use crate::{ClientMessage, PlayerInfo, ServerMessage};
+
+	fn valid_packets() -> (Vec<u8>, Vec<u8>) {
+		let server = Packet::unconnected(7, ServerMessage::PlayersJoined(vec![PlayerInfo { net_id: 3, name: "someone".into() }]));
+		let client = Packet::unconnected(7, ClientMessage::Login("someone".into()));
+		(server.encode().unwrap(), client.encode().unwrap())
+	}
+
+	/// decodes with both message types, a panic fails the test on its own
+	fn decode_both(bytes: &[u8]) -> (Result<Packet<ServerMessage>, DecodeError>, Result<Packet<ClientMessage>, DecodeError>) {
+		(Packet::<ServerMessage>::decode(bytes), Packet::<ClientMessage>::decode(bytes))
+	}
+
+	#[test]
+	fn random_bytes_are_rejected() {
+		let mut rng = StdRng::seed_from_u64(1);
+		for _ in 0..5_000 {
+			let len = rng.random_range(0..MAX_PACKET_SIZE + 100);
+			let bytes: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+			let (server, client) = decode_both(&bytes);
+			assert!(server.is_err() && client.is_err(), "random bytes decoded: {:?}", bytes);
+		}
+	}
+
+	#[test]
+	fn truncated_packets_are_rejected() {
+		let (server, client) = valid_packets();
+		for len in 0..server.len() {
+			assert!(Packet::<ServerMessage>::decode(&server[..len]).is_err(), "server packet cut to {} bytes decoded", len);
+		}
+		for len in 0..client.len() {
+			assert!(Packet::<ClientMessage>::decode(&client[..len]).is_err(), "client packet cut to {} bytes decoded", len);
+		}
+	}
+
+	#[test]
+	fn mutated_packets_do_not_panic() {
+		let mut rng = StdRng::seed_from_u64(2);
+		let (server, client) = valid_packets();
+		for _ in 0..5_000 {
+			let mut bytes = if rng.random_bool(0.5) { server.clone() } else { client.clone() };
+			// the body is mutated behind a valid prefix so the decoders get past the header checks
+			for _ in 0..rng.random_range(1..4) {
+				let index = rng.random_range(6..bytes.len());
+				bytes[index] = rng.random();
+			}
+			if rng.random_bool(0.3) {
+				bytes.extend((0..rng.random_range(0..16)).map(|_| rng.random::<u8>()));
+			}
+			let _ = decode_both(&bytes);
+		}
+	}
+
+	#[test]
+	fn malformed_packets_report_their_error() {
+		let (server, client) = valid_packets();
+		assert!(Packet::<ServerMessage>::decode(&server).is_ok());
+		assert!(Packet::<ClientMessage>::decode(&client).is_ok());
+
+		let mut bad_magic = server.clone();
+		bad_magic[0] = b'X';
+		assert!(matches!(decode_both(&bad_magic), (Err(DecodeError::BadMagic), Err(DecodeError::BadMagic))));
+
+		let mut newer = Packet::unconnected(7, ClientMessage::Login("someone".into()));
+		newer.version = PROTOCOL_VERSION + 1;
+		let newer = newer.encode().unwrap();
+		let expected = PROTOCOL_VERSION + 1;
+		assert!(matches!(decode_both(&newer), (Err(DecodeError::VersionMismatch(a)), Err(DecodeError::VersionMismatch(b))) if a == expected && b == expected));
+
+		let mut trailing = server.clone();
+		trailing.push(0);
+		assert!(matches!(Packet::<ServerMessage>::decode(&trailing), Err(DecodeError::TrailingBytes(1))));
+		let mut trailing = client.clone();
+		trailing.push(0);
+		assert!(matches!(Packet::<ClientMessage>::decode(&trailing), Err(DecodeError::TrailingBytes(1))));
+
+		let mut too_large = server.clone();
+		too_large.resize(MAX_PACKET_SIZE + 1, 0);
+		assert!(matches!(decode_both(&too_large), (Err(DecodeError::TooLarge(a)), Err(DecodeError::TooLarge(b))) if a == MAX_PACKET_SIZE + 1 && b == MAX_PACKET_SIZE + 1));
+	}
+
+	#[test]
+	fn resends_stop_after_the_limit() {
+		let start = Instant::now();
+		let mut connection = Connection::<ServerMessage, ClientMessage>::new(start);
+		connection.send(ServerMessage::PlayerLeft(1), 0, start).unwrap();
+		let mut resent = 0;
+		for step in 1..=MAX_RESENDS * 2 {
+			resent += connection.resend(start + RESEND_INTERVAL * step).len();
+		}
+		assert_eq!(resent, MAX_RESENDS as usize);
+		assert!(connection.gave_up());
+		assert!(connection.next_resend().is_none());
+	}
+
+	#[test]
+	fn messages_far_ahead_are_not_buffered() {
+		let now = Instant::now();
+		let mut sender = Connection::<ServerMessage, ClientMessage>::new(now);
+		let mut receiver = Connection::<ClientMessage, ServerMessage>::new(now);
+		let packets: Vec<_> = (0..REORDER_WINDOW as u32 + 10).map(|id| sender.send(ServerMessage::PlayerLeft(id), 0, now).unwrap()).collect();
+
+		// everything but the first arrives, only the window is kept and acked
+		for bytes in &packets[1..] {
+			assert!(receiver.receive(bytes, now).unwrap().is_empty());
+		}
+		assert_eq!(receiver.out_of_order.len(), REORDER_WINDOW as usize - 1);
+		assert_eq!(receiver.ack, Some(REORDER_WINDOW - 1));
+
+		let delivered = receiver.receive(&packets[0], now).unwrap();
+		assert_eq!(delivered.len(), REORDER_WINDOW as usize);
+		assert!(receiver.out_of_order.is_empty());
+	}
+
+	#[test]
+	fn forged_lengths_do_not_allocate() {
+		// a login whose name claims to be four gigabytes long
+		let mut bytes = Packet::unconnected(0, ClientMessage::Login(String::new())).encode().unwrap();
+		bytes.pop();
+		bytes.extend([0xFC, 0xFF, 0xFF, 0xFF, 0xFF]);
+		assert!(Packet::<ClientMessage>::decode(&bytes).is_err());
+	}
+}
@@ -0,0 +1,391 @@
+use avian2d::prelude::*;
+use bevy::prelude::*;
+
+use crate::{Areas, CursorPos, Enemy, Player, PlayerInput, PlayerState, PlayerStats, PrimaryWindow, Radius, SpatialGrid, Velocity};
+
+pub const PLAYER_RADIUS: f32 = 20.;
+/// top speed in units per second, reached when the cursor is PLAYER_SPEED_DISTANCE or further from the center
+pub const PLAYER_SPEED: f32 = 300.;
+pub const PLAYER_SPEED_DISTANCE: f32 = 200.;
+pub const WALL_THICKNESS: f32 = 10.;
+pub const SAFE_ZONE_WIDTH: f32 = 300.;
+/// seconds a downed player has to be touched by a teammate before it is dead
+pub const DOWNED_DURATION: f32 = 10.;
+/// seconds a dead player still lies around before it turns into a spectator
+pub const DEAD_DURATION: f32 = 3.;
+
+/// the player this instance steers with its own input
+#[derive(Component)]
+pub struct Controlled;
+
+/// movement of the controlled player in this frame, already applied to its transform
+#[derive(Message, Clone, Copy)]
+pub struct LocalInput(pub PlayerInput);
+
+/// the player ran out of time while downed, written once when it turns dead
+#[derive(Message, Clone, Copy)]
+pub struct PlayerDied(pub Entity);
+
+#[derive(PhysicsLayer, Clone, Copy, Debug, Default)]
+pub enum Layer {
+    #[default]
+    Boundary,
+    Ball,
+    Player,
+    SafeZone,
+}
+
+/// enemies bounce off its edges, players inside cannot be downed
+#[derive(Component)]
+pub struct SafeZone(pub Rect);
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InputSet;
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationSet;
+
+/// turns the cursor into the velocity of the controlled player
+pub struct InputPlugin;
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CursorPos(Vec2::ZERO))
+            .configure_sets(Update, InputSet.before(SimulationSet))
+            .add_systems(Update, (cursor_position_system, player_movement_system).chain().in_set(InputSet));
+    }
+}
+
+/// the game rules, shared by the offline mode, the server and the client prediction
+pub struct GameSimulationPlugin {
+	pub authoritative: bool, // enemy physics and deaths only run where the game is decided, clients just predict their own movement
+}
+
+impl Plugin for GameSimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<LocalInput>()
+            .add_systems(Update, move_controlled_system.in_set(SimulationSet));
+
+        if self.authoritative {
+            app.add_message::<PlayerDied>()
+                .add_plugins(PhysicsPlugins::default())
+                .insert_resource(Gravity::ZERO)
+                .insert_resource(SpatialGrid::default())
+                .add_systems(FixedUpdate, (rebuild_spatial_grid, (enemy_kill_system, player_state_system).chain()).in_set(SimulationSet))
+                .add_systems(FixedUpdate, portal_system.run_if(resource_exists::<Areas>).in_set(SimulationSet).before(enemy_kill_system));
+        }
+    }
+}
+
+/// players are moved by their input, not by the physics engine, their sensor only reports touching enemies
+pub fn player_bundle(position: Vec2) -> impl Bundle {
+    (
+        Transform::from_translation(position.extend(1.)),
+        RigidBody::Kinematic,
+        Collider::circle(PLAYER_RADIUS),
+        Sensor,
+        CollidingEntities::default(),
+        CollisionLayers::new([Layer::Player], [Layer::Ball]),
+        Player,
+        PlayerState::Alive,
+        PlayerStats::default(),
+        Radius(PLAYER_RADIUS),
+        Velocity(Vec2::ZERO),
+    )
+}
+
+/// enemies are moved by the physics engine and bounce off the boundary without losing speed
+pub fn enemy_bundle(position: Vec2, velocity: Vec2, radius: f32) -> impl Bundle {
+    (
+        Transform::from_translation(position.extend(0.)),
+        RigidBody::Dynamic,
+        Collider::circle(radius),
+        LinearVelocity(velocity),
+        CollisionLayers::new([Layer::Ball], [Layer::Boundary, Layer::Player, Layer::SafeZone]),
+        Restitution::new(1.0), // Perfect bounce (1.0 = 100% energy retained)
+        Friction::ZERO.with_combine_rule(CoefficientCombine::Min), // Remove friction
+        Enemy,
+        Radius(radius),
+    )
+}
+
+pub fn wall_bundle(position: Vec2, size: Vec2) -> impl Bundle {
+    (
+        Transform::from_translation(position.extend(0.)),
+        RigidBody::Static,
+        Collider::rectangle(size.x, size.y),
+        CollisionLayers::new([Layer::Boundary], [Layer::Ball]),
+    )
+}
+
+/// players pass through it, so it only has to stop the enemies
+pub fn safe_zone_bundle(zone: Rect) -> impl Bundle {
+    (
+        Transform::from_translation(zone.center().extend(0.)),
+        RigidBody::Static,
+        Collider::rectangle(zone.width(), zone.height()),
+        CollisionLayers::new([Layer::SafeZone], [Layer::Ball]),
+        SafeZone(zone),
+    )
+}
+
+/// the start and the end of an area, spanning its full height at the left and the right edge
+pub fn safe_zones(bounds: Rect, width: f32) -> [Rect; 2] {
+    [
+        Rect::new(bounds.min.x, bounds.min.y, bounds.min.x + width, bounds.max.y),
+        Rect::new(bounds.max.x - width, bounds.min.y, bounds.max.x, bounds.max.y),
+    ]
+}
+
+/// position and size of the four walls around an area
+pub fn boundary_walls(bounds: Rect) -> [(Vec2, Vec2); 4] {
+    let center = bounds.center();
+    let vertical = Vec2::new(WALL_THICKNESS, bounds.height());
+    let horizontal = Vec2::new(bounds.width(), WALL_THICKNESS);
+    [
+        (Vec2::new(bounds.min.x, center.y), vertical),
+        (Vec2::new(bounds.max.x, center.y), vertical),
+        (Vec2::new(center.x, bounds.min.y), horizontal),
+        (Vec2::new(center.x, bounds.max.y), horizontal),
+    ]
+}
+
+fn cursor_position_system(
+    window: Single<&Window, With<PrimaryWindow>>,
+    mut cursor: ResMut<CursorPos>,
+) {
+    let window_center = Vec2::new(window.width() / 2.0, window.height() / 2.0);
+
+    if let Some(cursor_position) = window.cursor_position() {
+        cursor.0 = (cursor_position - window_center) * Vec2::new(1., -1.); // relative to center
+    }
+}
+
+fn player_movement_system(
+    cursor: Res<CursorPos>,
+    query: Query<(&mut Velocity, &PlayerState), (With<Player>, With<Controlled>)>,
+) {
+    for (mut velocity, state) in query {
+        if *state == PlayerState::Alive {
+            let length = cursor.0.length();
+            if length == 0. {
+                continue;
+            }
+            let percentage = (length / PLAYER_SPEED_DISTANCE).min(1.); // the server does not accept more
+
+            velocity.0 = cursor.0.normalize() * percentage * PLAYER_SPEED;
+        }
+        else {
+            velocity.0 = Vec2::ZERO;
+        }
+    }
+}
+
+/// moves the controlled player the same way the server moves it for a received input
+fn move_controlled_system(
+    time: Res<Time>,
+    query: Query<(&mut Transform, &Velocity), (With<Player>, With<Controlled>)>,
+    mut local_inputs: MessageWriter<LocalInput>,
+    mut next_sequence: Local<u32>,
+) {
+    for (mut transform, velocity) in query {
+        let input = PlayerInput {
+            sequence: *next_sequence,
+            dt: time.delta_secs(),
+            velocity: velocity.0.into(),
+        };
+        *next_sequence += 1;
+
+        input.apply(&mut transform.translation);
+        local_inputs.write(LocalInput(input));
+    }
+}
+
+/// puts every enemy into the grid at its position of this tick, the server looks up what each player gets to see in it
+fn rebuild_spatial_grid(
+    mut grid: ResMut<SpatialGrid>,
+    enemies: Query<(Entity, &Transform, &Radius), (With<Enemy>, Without<RigidBodyDisabled>)>,
+) {
+    grid.clear();
+    for (entity, transform, radius) in &enemies {
+        grid.insert(entity, transform.translation.truncate(), radius.0);
+    }
+}
+
+/// keeps players inside their area and moves alive ones standing in a portal to the neighbouring area
+fn portal_system(
+    areas: Res<Areas>,
+    players: Query<(&mut Transform, &PlayerState, &Radius), With<Player>>,
+) {
+    for (mut transform, state, radius) in players {
+        let index = areas.index_at(transform.translation.truncate());
+        let area = &areas.0[index];
+        // players do not collide with walls, so they are held back here instead
+        let bounds = area.bounds();
+        let position = transform.translation.truncate().clamp(bounds.min + radius.0, bounds.max - radius.0);
+
+        let [back, forward] = area.portals();
+        let destination = if *state != PlayerState::Alive {
+            None
+        }
+        else if forward.contains(position) {
+            areas.0.get(index + 1).map(|next| next.start())
+        }
+        else if back.contains(position) {
+            index.checked_sub(1).map(|previous| areas.0[previous].end())
+        }
+        else {
+            None
+        };
+
+        transform.translation = destination.unwrap_or(position).extend(transform.translation.z);
+    }
+}
+
+/// whether an enemy touches the player outside of a safe zone, which downs an alive player
+fn touches_enemy(
+    colliding: &CollidingEntities,
+    position: Vec2,
+    enemies: &Query<(), With<Enemy>>,
+    safe_zones: &Query<&SafeZone>,
+) -> bool {
+    colliding.iter().any(|entity| enemies.contains(*entity)) && !safe_zones.iter().any(|zone| zone.0.contains(position))
+}
+
+/// downs alive players whose sensor touches an enemy, whatever shape the enemy has
+/// checked on every tick and not only when the contact starts, so leaving a safe zone next to an enemy is not safe
+fn enemy_kill_system(
+    mut players: Query<(&mut PlayerState, &mut PlayerStats, &Transform, &CollidingEntities), With<Player>>,
+    enemies: Query<(), With<Enemy>>,
+    safe_zones: Query<&SafeZone>,
+) {
+    for (mut state, mut stats, transform, colliding) in &mut players {
+        if *state != PlayerState::Alive || !touches_enemy(colliding, transform.translation.truncate(), &enemies, &safe_zones) {
+            continue;
+        }
+        *state = PlayerState::Downed(DOWNED_DURATION);
+        stats.downs += 1;
+    }
+}
+
+/// runs down the timers of downed and dead players and revives those an alive teammate touches
+/// a downed player an enemy still touches is not revived, it would be downed again on the next tick with a fresh timer
+fn player_state_system(
+    time: Res<Time>,
+    mut players: Query<(Entity, &mut PlayerState, &mut PlayerStats, &Transform, &Radius, &CollidingEntities), With<Player>>,
+    enemies: Query<(), With<Enemy>>,
+    safe_zones: Query<&SafeZone>,
+    mut deaths: MessageWriter<PlayerDied>,
+) {
+    let rescuers: Vec<(Entity, Vec2, f32)> = players
+        .iter()
+        .filter(|(_, state, _, _, _, _)| **state == PlayerState::Alive)
+        .map(|(entity, _, _, transform, radius, _)| (entity, transform.translation.truncate(), radius.0))
+        .collect();
+    let mut revivers = Vec::new();
+
+    for (entity, mut state, mut stats, transform, radius, colliding) in &mut players {
+        match *state {
+            PlayerState::Downed(remaining) => {
+                stats.survival_time += time.delta_secs();
+                let position = transform.translation.truncate();
+                let reviver = rescuers.iter().find(|(_, rescuer, rescuer_radius)| rescuer.distance(position) <= radius.0 + rescuer_radius);
+                if let Some((reviver, _, _)) = reviver.filter(|_| !touches_enemy(colliding, position, &enemies, &safe_zones)) {
+                    *state = PlayerState::Alive;
+                    revivers.push(*reviver);
+                    continue;
+                }
+
+                let remaining = remaining - time.delta_secs();
+                if remaining <= 0. {
+                    *state = PlayerState::Dead(DEAD_DURATION);
+                    deaths.write(PlayerDied(entity));
+                }
+                else {
+                    *state = PlayerState::Downed(remaining);
+                }
+            },
+            PlayerState::Dead(remaining) => {
+                let remaining = remaining - time.delta_secs();
+                *state = if remaining <= 0. { PlayerState::Spectating } else { PlayerState::Dead(remaining) };
+            },
+            PlayerState::Alive => stats.survival_time += time.delta_secs(),
+            PlayerState::Spectating => {},
+        }
+    }
+
+    for reviver in revivers {
+        if let Ok((_, _, mut stats, _, _, _)) = players.get_mut(reviver) {
+            stats.revives += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+
+    /// the authoritative simulation alone, every update runs exactly one tick
+    fn test_app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, AssetPlugin::default()))
+            .add_plugins(GameSimulationPlugin { authoritative: true })
+            .insert_resource(Time::<Fixed>::from_hz(60.))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(1. / 60.)));
+        app.finish();
+        app.cleanup();
+        app
+    }
+
+    fn run(app: &mut App, ticks: usize) {
+        for _ in 0..ticks {
+            app.update();
+        }
+    }
+
+    #[test]
+    fn leaving_a_safe_zone_next_to_an_enemy_downs() {
+        let mut app = test_app();
+        let [zone, _] = safe_zones(Rect::from_center_half_size(Vec2::ZERO, Vec2::splat(1000.)), 300.);
+        app.world_mut().spawn(safe_zone_bundle(zone));
+        // the enemy reaches into the safe zone up to the player, which stands right behind its edge
+        app.world_mut().spawn(enemy_bundle(Vec2::new(zone.max.x + 45., 0.), Vec2::ZERO, 40.));
+        let player = app.world_mut().spawn(player_bundle(Vec2::new(zone.max.x - 2., 0.))).id();
+        run(&mut app, 10);
+        assert_eq!(app.world().get::<PlayerState>(player), Some(&PlayerState::Alive));
+
+        app.world_mut().get_mut::<Transform>(player).unwrap().translation.x = zone.max.x + 10.;
+        run(&mut app, 3);
+        assert!(matches!(app.world().get::<PlayerState>(player), Some(PlayerState::Downed(_))));
+    }
+
+    #[test]
+    fn revived_next_to_an_enemy_is_downed_again() {
+        let mut app = test_app();
+        let enemy = app.world_mut().spawn(enemy_bundle(Vec2::new(30., 0.), Vec2::ZERO, 20.)).id();
+        let downed = app.world_mut().spawn(player_bundle(Vec2::ZERO)).insert(PlayerState::Downed(DOWNED_DURATION)).id();
+        let rescuer = app.world_mut().spawn(player_bundle(Vec2::new(-500., 0.))).id();
+        // the contact starts while the player is down, the rescuer only comes afterwards
+        run(&mut app, 5);
+        app.world_mut().get_mut::<Transform>(rescuer).unwrap().translation.x = -35.;
+        run(&mut app, 10);
+        // nobody is revived into an enemy, the timer keeps running
+        assert_eq!(app.world().get::<PlayerStats>(rescuer).unwrap().revives, 0);
+        assert!(matches!(app.world().get::<PlayerState>(downed), Some(PlayerState::Downed(remaining)) if *remaining < DOWNED_DURATION));
+
+        app.world_mut().get_mut::<Transform>(enemy).unwrap().translation.x = 500.;
+        run(&mut app, 3);
+        assert_eq!(app.world().get::<PlayerState>(downed), Some(&PlayerState::Alive));
+
+        // the enemy comes back while the rescuer is still there
+        app.world_mut().get_mut::<Transform>(enemy).unwrap().translation.x = 30.;
+        run(&mut app, 10);
+        assert_eq!(app.world().get::<PlayerStats>(rescuer).unwrap().revives, 1);
+        assert_eq!(app.world().get::<PlayerStats>(downed).unwrap().downs, 1);
+        assert!(matches!(app.world().get::<PlayerState>(downed), Some(PlayerState::Downed(_))));
+        assert_eq!(app.world().get::<PlayerState>(rescuer), Some(&PlayerState::Alive));
+    }
+}
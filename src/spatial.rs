@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use bevy::prelude::*;
+
+/// cell size of the grid the simulation uses, about twice the radius of a typical enemy
+pub const GRID_CELL_SIZE: f32 = 100.;
+
+/// uniform grid of circles, rebuilt every tick so lookups only look at nearby cells instead of everything
+#[derive(Resource)]
+pub struct SpatialGrid {
+	cell_size: f32,
+	cells: HashMap<(i32, i32), Vec<(Entity, Vec2, f32)>>,
+	max_radius: f32, // biggest circle inserted, queries widen their search by it
+}
+
+impl Default for SpatialGrid {
+	fn default() -> Self {
+		Self::new(GRID_CELL_SIZE)
+	}
+}
+
+impl SpatialGrid {
+	pub fn new(cell_size: f32) -> Self {
+		Self {
+			cell_size,
+			cells: HashMap::new(),
+			max_radius: 0.,
+		}
+	}
+
+	fn cell(&self, position: Vec2) -> (i32, i32) {
+		let cell = (position / self.cell_size).floor();
+		(cell.x as i32, cell.y as i32)
+	}
+
+	/// empties the cells but keeps their allocations for the next rebuild
+	pub fn clear(&mut self) {
+		for entries in self.cells.values_mut() {
+			entries.clear();
+		}
+		self.max_radius = 0.;
+	}
+
+	pub fn insert(&mut self, entity: Entity, position: Vec2, radius: f32) {
+		let cell = self.cell(position);
+		self.cells.entry(cell).or_default().push((entity, position, radius));
+		self.max_radius = self.max_radius.max(radius);
+	}
+
+	/// every circle that overlaps the given one, with its position and radius
+	pub fn query(&self, center: Vec2, radius: f32) -> impl Iterator<Item = (Entity, Vec2, f32)> + '_ {
+		let reach = Vec2::splat(radius + self.max_radius);
+		let (min_x, min_y) = self.cell(center - reach);
+		let (max_x, max_y) = self.cell(center + reach);
+		(min_x..=max_x)
+			.flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+			.filter_map(|cell| self.cells.get(&cell))
+			.flatten()
+			.filter(move |(_, position, entry_radius)| {
+				let distance = radius + entry_radius;
+				position.distance_squared(center) <= distance * distance
+			})
+			.copied()
+	}
+}